@@ -0,0 +1,170 @@
+use std::{fmt, fmt::Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+    // Only hex and triangle grids use the diagonals
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+}
+
+impl Direction {
+    pub const SQUARE: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
+
+    pub const HEX: [Direction; 6] = [
+        Direction::Left,
+        Direction::Right,
+        Direction::UpLeft,
+        Direction::UpRight,
+        Direction::DownLeft,
+        Direction::DownRight,
+    ];
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::UpLeft => Direction::DownRight,
+            Direction::UpRight => Direction::DownLeft,
+            Direction::DownLeft => Direction::UpRight,
+            Direction::DownRight => Direction::UpLeft,
+        }
+    }
+
+    // LURD notation: lower case for a walk, upper case for a push. The diagonals take
+    // the letters around S on the keyboard
+    pub fn to_lurd(self, push: bool) -> char {
+        let c = match self {
+            Direction::Up => 'u',
+            Direction::Down => 'd',
+            Direction::Left => 'l',
+            Direction::Right => 'r',
+            Direction::UpLeft => 'q',
+            Direction::UpRight => 'e',
+            Direction::DownLeft => 'z',
+            Direction::DownRight => 'c',
+        };
+        if push {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        }
+    }
+
+    pub fn from_lurd(c: char) -> Option<Direction> {
+        match c.to_ascii_lowercase() {
+            'u' => Some(Direction::Up),
+            'd' => Some(Direction::Down),
+            'l' => Some(Direction::Left),
+            'r' => Some(Direction::Right),
+            'q' => Some(Direction::UpLeft),
+            'e' => Some(Direction::UpRight),
+            'z' => Some(Direction::DownLeft),
+            'c' => Some(Direction::DownRight),
+            _ => None,
+        }
+    }
+}
+
+// Whitespace is allowed between moves, anything else is reported back as the offending character
+pub fn parse_lurd(lurd: &str) -> Result<Vec<Direction>, char> {
+    lurd.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| Direction::from_lurd(c).ok_or(c))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Move(Direction),
+    Undo,
+    Redo,
+    Restart,
+    Hint,
+    Menu,
+    SwitchPlayer,
+    // Only queued when stepping back through a replay, so it isn't in `ALL` to be bound
+    SwitchPlayerBack,
+}
+
+impl Action {
+    // Every bindable action, in the order the rebinding screen walks through them
+    pub const ALL: [Action; 14] = [
+        Action::Move(Direction::Up),
+        Action::Move(Direction::Down),
+        Action::Move(Direction::Left),
+        Action::Move(Direction::Right),
+        Action::Move(Direction::UpLeft),
+        Action::Move(Direction::UpRight),
+        Action::Move(Direction::DownLeft),
+        Action::Move(Direction::DownRight),
+        Action::Undo,
+        Action::Redo,
+        Action::Restart,
+        Action::Hint,
+        Action::Menu,
+        Action::SwitchPlayer,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Move(Direction::Up) => "move_up",
+            Action::Move(Direction::Down) => "move_down",
+            Action::Move(Direction::Left) => "move_left",
+            Action::Move(Direction::Right) => "move_right",
+            Action::Move(Direction::UpLeft) => "move_up_left",
+            Action::Move(Direction::UpRight) => "move_up_right",
+            Action::Move(Direction::DownLeft) => "move_down_left",
+            Action::Move(Direction::DownRight) => "move_down_right",
+            Action::Undo => "undo",
+            Action::Redo => "redo",
+            Action::Restart => "restart",
+            Action::Hint => "hint",
+            Action::Menu => "menu",
+            Action::SwitchPlayer => "switch_player",
+            Action::SwitchPlayerBack => "switch_player_back",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL
+            .iter()
+            .copied()
+            .find(|action| action.name() == name)
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Action::Move(Direction::Up) => "Move up",
+            Action::Move(Direction::Down) => "Move down",
+            Action::Move(Direction::Left) => "Move left",
+            Action::Move(Direction::Right) => "Move right",
+            Action::Move(Direction::UpLeft) => "Move up-left",
+            Action::Move(Direction::UpRight) => "Move up-right",
+            Action::Move(Direction::DownLeft) => "Move down-left",
+            Action::Move(Direction::DownRight) => "Move down-right",
+            Action::Undo => "Undo",
+            Action::Redo => "Redo",
+            Action::Restart => "Restart",
+            Action::Hint => "Hint",
+            Action::Menu => "Menu",
+            Action::SwitchPlayer => "Switch player",
+            Action::SwitchPlayerBack => "Switch player back",
+        })?;
+        Ok(())
+    }
+}
@@ -5,6 +5,7 @@ use std::collections::HashMap;
 #[derive(Default)]
 pub struct AudioStore {
     pub sounds: HashMap<String, audio::Source>,
+    pub music: Option<audio::Source>,
 }
 
 impl AudioStore {
@@ -19,7 +20,15 @@ impl AudioStore {
 
 pub fn initialize_sounds(world: &mut World, context: &mut Context) {
     let mut audio_store = world.write_resource::<AudioStore>();
-    let sounds = ["correct", "incorrect", "wall"];
+    let sounds = [
+        "correct",
+        "incorrect",
+        "wall",
+        "teleport",
+        "hole",
+        "key",
+        "door",
+    ];
 
     for sound in sounds.iter() {
         let sound_name = sound.to_string();
@@ -29,3 +38,14 @@ pub fn initialize_sounds(world: &mut World, context: &mut Context) {
         audio_store.sounds.insert(sound_name, source);
     }
 }
+
+// Loop the level's music, if it has any; a missing file just means silence
+pub fn play_music(world: &mut World, context: &mut Context, path: &str) {
+    let mut audio_store = world.write_resource::<AudioStore>();
+
+    audio_store.music = audio::Source::new(context, path).ok().map(|mut source| {
+        source.set_repeat(true);
+        let _ = source.play();
+        source
+    });
+}
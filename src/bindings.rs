@@ -0,0 +1,237 @@
+use crate::actions::{Action, Direction};
+use crate::constants::{REPEAT_DELAY, REPEAT_INTERVAL};
+use ggez::{event::KeyCode, filesystem, Context, GameResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+const BINDINGS_PATH: &str = "/bindings.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Arrows,
+    Wasd,
+    Vi,
+    Numpad,
+}
+
+impl Preset {
+    fn keys(self) -> Vec<(Action, Vec<&'static str>)> {
+        let (up, down, left, right) = match self {
+            Preset::Arrows => ("Up", "Down", "Left", "Right"),
+            Preset::Wasd => ("W", "S", "A", "D"),
+            Preset::Vi => ("K", "J", "H", "L"),
+            Preset::Numpad => ("Numpad8", "Numpad2", "Numpad4", "Numpad6"),
+        };
+
+        // Vi keys take H for moving left, so the hint moves to the help key
+        let (undo, hint) = match self {
+            Preset::Vi => ("U", "Slash"),
+            Preset::Numpad => ("Numpad0", "H"),
+            _ => ("Z", "H"),
+        };
+
+        vec![
+            (Action::Move(Direction::Up), vec![up]),
+            (Action::Move(Direction::Down), vec![down]),
+            (Action::Move(Direction::Left), vec![left]),
+            (Action::Move(Direction::Right), vec![right]),
+            // The hex diagonals, on the corners of the numpad in every preset
+            (Action::Move(Direction::UpLeft), vec!["Numpad7", "Home"]),
+            (Action::Move(Direction::UpRight), vec!["Numpad9", "PageUp"]),
+            (Action::Move(Direction::DownLeft), vec!["Numpad1", "End"]),
+            (
+                Action::Move(Direction::DownRight),
+                vec!["Numpad3", "PageDown"],
+            ),
+            (Action::Undo, vec![undo, "Back"]),
+            (Action::Redo, vec!["Y"]),
+            (Action::Restart, vec!["R"]),
+            (Action::Hint, vec![hint]),
+            (Action::Menu, vec!["Escape"]),
+            (Action::SwitchPlayer, vec!["Tab"]),
+        ]
+    }
+}
+
+// The file format: an optional preset, then per-action key lists that replace the preset's keys
+#[derive(Default, Serialize, Deserialize)]
+struct BindingsConfig {
+    #[serde(default)]
+    preset: Option<Preset>,
+    #[serde(default)]
+    repeat_delay_ms: Option<u64>,
+    #[serde(default)]
+    repeat_interval_ms: Option<u64>,
+    #[serde(default)]
+    actions_per_tick: Option<usize>,
+    #[serde(default)]
+    keys: BTreeMap<String, Vec<String>>,
+}
+
+pub struct KeyBindings {
+    // Keyed by the name of the key code, e.g. "Up", "W" or "Numpad8"
+    actions: HashMap<String, Action>,
+    pub repeat_delay: Duration,
+    pub repeat_interval: Duration,
+    pub actions_per_tick: usize,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings::from_preset(Preset::Arrows)
+    }
+}
+
+impl KeyBindings {
+    pub fn from_preset(preset: Preset) -> Self {
+        let mut bindings = KeyBindings {
+            actions: HashMap::new(),
+            repeat_delay: REPEAT_DELAY,
+            repeat_interval: REPEAT_INTERVAL,
+            actions_per_tick: 1,
+        };
+
+        for (action, keys) in preset.keys() {
+            for key in keys {
+                bindings.actions.insert(key.to_string(), action);
+            }
+        }
+
+        bindings
+    }
+
+    pub fn action_for(&self, keycode: KeyCode) -> Option<Action> {
+        self.actions.get(&key_name(keycode)).copied()
+    }
+
+    pub fn keys_for(&self, action: Action) -> Vec<&str> {
+        let mut keys = self
+            .actions
+            .iter()
+            .filter(|(_key, bound)| **bound == action)
+            .map(|(key, _bound)| key.as_str())
+            .collect::<Vec<&str>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    // Add `keycode` to the keys for `action`, taking it away from whatever it was bound to.
+    // Refused if that would take the last key away from the menu, returning whether it was bound
+    pub fn rebind(&mut self, action: Action, keycode: KeyCode) -> bool {
+        let key = key_name(keycode);
+        let is_last_menu_key =
+            self.actions.get(&key) == Some(&Action::Menu) && self.keys_for(Action::Menu).len() == 1;
+        if action != Action::Menu && is_last_menu_key {
+            return false;
+        }
+
+        self.actions.insert(key, action);
+        true
+    }
+
+    fn apply(&mut self, action: Action, keys: &[String]) {
+        self.actions.retain(|_key, bound| *bound != action);
+        for key in keys {
+            self.actions.insert(key.clone(), action);
+        }
+    }
+}
+
+fn key_name(keycode: KeyCode) -> String {
+    format!("{:?}", keycode)
+}
+
+pub fn load_bindings(context: &mut Context) -> KeyBindings {
+    let mut contents = String::new();
+
+    match filesystem::open(context, BINDINGS_PATH) {
+        Ok(mut file) => {
+            if file.read_to_string(&mut contents).is_err() {
+                return KeyBindings::default();
+            }
+        }
+        Err(_) => return KeyBindings::default(),
+    }
+
+    let config: BindingsConfig = toml::from_str(&contents).unwrap_or_default();
+    let mut bindings = KeyBindings::from_preset(config.preset.unwrap_or(Preset::Arrows));
+
+    if let Some(delay) = config.repeat_delay_ms {
+        bindings.repeat_delay = Duration::from_millis(delay);
+    }
+    if let Some(interval) = config.repeat_interval_ms {
+        bindings.repeat_interval = Duration::from_millis(interval.max(1));
+    }
+    if let Some(actions_per_tick) = config.actions_per_tick {
+        bindings.actions_per_tick = actions_per_tick.max(1);
+    }
+
+    // Unknown action names are skipped rather than failing the whole file
+    for (name, keys) in config.keys.iter() {
+        if let Some(action) = Action::from_name(name) {
+            bindings.apply(action, keys);
+        }
+    }
+
+    // Without a menu key there would be no way back to the menu
+    if bindings.keys_for(Action::Menu).is_empty() {
+        bindings
+            .actions
+            .insert(key_name(KeyCode::Escape), Action::Menu);
+    }
+
+    bindings
+}
+
+pub fn save_bindings(context: &mut Context, bindings: &KeyBindings) -> GameResult {
+    let config = BindingsConfig {
+        preset: None,
+        repeat_delay_ms: Some(bindings.repeat_delay.as_millis() as u64),
+        repeat_interval_ms: Some(bindings.repeat_interval.as_millis() as u64),
+        actions_per_tick: Some(bindings.actions_per_tick),
+        keys: Action::ALL
+            .iter()
+            .map(|action| {
+                let keys = bindings.keys_for(*action);
+                (
+                    action.name().to_string(),
+                    keys.iter().map(|key| key.to_string()).collect(),
+                )
+            })
+            .collect(),
+    };
+
+    let contents = toml::to_string(&config).expect("Expected bindings to serialize");
+    let mut file = filesystem::create(context, BINDINGS_PATH)?;
+
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebinding_adds_a_key_and_takes_it_from_its_old_action() {
+        let mut bindings = KeyBindings::from_preset(Preset::Arrows);
+        assert!(bindings.rebind(Action::Undo, KeyCode::R));
+
+        assert_eq!(bindings.keys_for(Action::Undo), vec!["Back", "R", "Z"]);
+        assert!(bindings.keys_for(Action::Restart).is_empty());
+    }
+
+    #[test]
+    fn the_last_menu_key_cant_be_rebound() {
+        let mut bindings = KeyBindings::from_preset(Preset::Arrows);
+        assert!(!bindings.rebind(Action::Undo, KeyCode::Escape));
+        assert_eq!(bindings.action_for(KeyCode::Escape), Some(Action::Menu));
+
+        assert!(bindings.rebind(Action::Menu, KeyCode::M));
+        assert!(bindings.rebind(Action::Undo, KeyCode::Escape));
+        assert_eq!(bindings.keys_for(Action::Menu), vec!["M"]);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::actions::Direction;
+use crate::components::*;
+use crate::topology::Topology;
+use specs::{join::Join, storage::MaskedStorage, world::Index, Entities, Storage, World, WorldExt};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+// A lookup of what occupies each cell, shared by movement and path planning so they agree on collisions
+pub struct Board {
+    pub movables: HashMap<(u8, u8), Index>,
+    pub immovables: HashMap<(u8, u8), Index>,
+    pub terrain: Terrain,
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Board {
+    pub fn new<M, I, P>(
+        entities: &Entities,
+        movables: &Storage<Movable, M>,
+        immovables: &Storage<Immovable, I>,
+        positions: &Storage<Position, P>,
+    ) -> Self
+    where
+        M: Deref<Target = MaskedStorage<Movable>>,
+        I: Deref<Target = MaskedStorage<Immovable>>,
+        P: Deref<Target = MaskedStorage<Position>>,
+    {
+        let (width, height) = positions.join().fold((0, 0), |(width, height), position| {
+            (width.max(position.x + 1), height.max(position.y + 1))
+        });
+
+        Board {
+            movables: (entities, movables, positions)
+                .join()
+                .map(|t| ((t.2.x, t.2.y), t.0.id()))
+                .collect(),
+            immovables: (entities, immovables, positions)
+                .join()
+                .map(|t| ((t.2.x, t.2.y), t.0.id()))
+                .collect(),
+            terrain: Terrain::default(),
+            width,
+            height,
+        }
+    }
+
+    pub fn with_terrain(self, terrain: Terrain) -> Self {
+        Board { terrain, ..self }
+    }
+
+    pub fn contains(&self, (x, y): (u8, u8)) -> bool {
+        x < self.width && y < self.height
+    }
+
+    // Nothing movable or immovable is in the way
+    pub fn is_empty(&self, cell: (u8, u8)) -> bool {
+        self.contains(cell)
+            && !self.movables.contains_key(&cell)
+            && !self.immovables.contains_key(&cell)
+    }
+}
+
+// The grid and the floor that change how things move, shared by the board and the solver
+#[derive(Clone, Default)]
+pub struct Terrain {
+    pub topology: Topology,
+    pub ice: HashSet<(u8, u8)>,
+    // Each teleporter pad's partner
+    pub teleporters: HashMap<(u8, u8), (u8, u8)>,
+    // Pads that only carry the player, leaving boxes pushed onto them where they are
+    pub player_only: HashSet<(u8, u8)>,
+    pub one_ways: HashMap<(u8, u8), OneWay>,
+}
+
+impl Terrain {
+    pub fn new<I, T, O, P>(
+        ices: &Storage<Ice, I>,
+        teleporters: &Storage<Teleporter, T>,
+        one_ways: &Storage<OneWay, O>,
+        positions: &Storage<Position, P>,
+    ) -> Self
+    where
+        I: Deref<Target = MaskedStorage<Ice>>,
+        T: Deref<Target = MaskedStorage<Teleporter>>,
+        O: Deref<Target = MaskedStorage<OneWay>>,
+        P: Deref<Target = MaskedStorage<Position>>,
+    {
+        Terrain {
+            topology: Topology::Square,
+            ice: (ices, positions).join().map(|t| (t.1.x, t.1.y)).collect(),
+            teleporters: pair_teleporters(
+                (teleporters, positions)
+                    .join()
+                    .map(|t| ((t.1.x, t.1.y), t.0.link)),
+            ),
+            player_only: (teleporters, positions)
+                .join()
+                .filter(|t| !t.0.boxes)
+                .map(|t| (t.1.x, t.1.y))
+                .collect(),
+            one_ways: (one_ways, positions)
+                .join()
+                .map(|t| ((t.1.x, t.1.y), *t.0))
+                .collect(),
+        }
+    }
+
+    pub fn from_world(world: &World) -> Self {
+        Terrain::new(
+            &world.read_storage::<Ice>(),
+            &world.read_storage::<Teleporter>(),
+            &world.read_storage::<OneWay>(),
+            &world.read_storage::<Position>(),
+        )
+        .with_topology(*world.read_resource::<Topology>())
+    }
+
+    pub fn with_topology(self, topology: Topology) -> Self {
+        Terrain { topology, ..self }
+    }
+
+    pub fn step(&self, cell: (u8, u8), direction: Direction) -> Option<(u8, u8)> {
+        self.topology.step(cell, direction)
+    }
+
+    pub fn directions(&self) -> &'static [Direction] {
+        self.topology.directions()
+    }
+
+    // Without any of it every walk can be walked back, which searches rely on to group cells
+    pub fn is_plain(&self) -> bool {
+        self.ice.is_empty() && self.teleporters.is_empty() && self.one_ways.is_empty()
+    }
+
+    // Whether one-way tiles let a player or box step from `from` in `direction`
+    pub fn allows(&self, from: (u8, u8), direction: Direction, is_box: bool) -> bool {
+        let holds = |cell: (u8, u8)| {
+            self.one_ways
+                .get(&cell)
+                .filter(|one_way| one_way.applies_to(is_box))
+                .map(|one_way| one_way.direction)
+        };
+
+        let leaving = holds(from).is_none_or(|arrow| arrow != direction.opposite());
+        let entering = self
+            .step(from, direction)
+            .and_then(holds)
+            .is_none_or(|arrow| arrow == direction);
+        leaving && entering
+    }
+
+    // The cells passed through by something that has just moved onto `from`: it keeps sliding
+    // across ice until the next cell isn't free
+    pub fn slide<F: Fn((u8, u8)) -> bool>(
+        &self,
+        from: (u8, u8),
+        direction: Direction,
+        is_box: bool,
+        is_free: F,
+    ) -> Vec<(u8, u8)> {
+        let mut path = Vec::new();
+        let mut cell = from;
+
+        while self.ice.contains(&cell) && self.allows(cell, direction, is_box) {
+            match self.step(cell, direction) {
+                Some(next) if is_free(next) => {
+                    path.push(next);
+                    cell = next;
+                }
+                _ => break,
+            }
+        }
+
+        path
+    }
+
+    // Where something that has just moved onto `from` comes to rest: the end of any slide,
+    // then through a teleporter if the pad it stops on has a free partner
+    pub fn settle<F: Fn((u8, u8)) -> bool>(
+        &self,
+        from: (u8, u8),
+        direction: Direction,
+        is_box: bool,
+        is_free: F,
+    ) -> (u8, u8) {
+        let stop = self
+            .slide(from, direction, is_box, &is_free)
+            .last()
+            .copied()
+            .unwrap_or(from);
+
+        self.teleport(stop, is_box, is_free).unwrap_or(stop)
+    }
+
+    // Where something resting on `pad` comes out, if it is a teleporter that carries it and
+    // the partner pad is free
+    pub fn teleport<F: Fn((u8, u8)) -> bool>(
+        &self,
+        pad: (u8, u8),
+        is_box: bool,
+        is_free: F,
+    ) -> Option<(u8, u8)> {
+        if is_box && self.player_only.contains(&pad) {
+            return None;
+        }
+        self.teleporters
+            .get(&pad)
+            .copied()
+            .filter(|partner| is_free(*partner))
+    }
+}
+
+// Pads are only linked in pairs; a link with one pad or more than two goes nowhere
+fn pair_teleporters<I: Iterator<Item = ((u8, u8), u8)>>(pads: I) -> HashMap<(u8, u8), (u8, u8)> {
+    let mut links: HashMap<u8, Vec<(u8, u8)>> = HashMap::new();
+    for (cell, link) in pads {
+        links.entry(link).or_default().push(cell);
+    }
+
+    links
+        .values()
+        .filter(|cells| cells.len() == 2)
+        .flat_map(|cells| vec![(cells[0], cells[1]), (cells[1], cells[0])])
+        .collect()
+}
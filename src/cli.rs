@@ -0,0 +1,392 @@
+use crate::actions::{parse_lurd, Action};
+use crate::components::*;
+use crate::formats::{write_collection, write_xsb, Format};
+use crate::generator::{generate, GeneratorOptions};
+use crate::levels::*;
+use crate::map::{grid_from_world, Grid};
+use crate::resources::*;
+use crate::solver::{solve, Puzzle};
+use crate::systems::{GameStateSystem, InputSystem, TriggerSystem};
+use crate::topology::Topology;
+use specs::{join::Join, RunNow, World, WorldExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const USAGE: &str = "Usage:
+    rust-sokoban [play <file> [--level N] [--reverse]]
+    rust-sokoban verify <level> <solution> [--level N] [--reverse]
+    rust-sokoban solve <level> [--level N]
+    rust-sokoban convert <in> <out>
+    rust-sokoban render <level> --ascii|--png <out.png> [--level N]
+    rust-sokoban stats <collection>
+    rust-sokoban generate <out> [--seed N] [--size WxH] [--boxes N] [--difficulty N]
+
+Level files are read and written by extension: .xsb or .sok, .rle, .json, .toml,
+and anything else in the token format. --reverse starts with the boxes on the goals,
+pulling them back to where the level starts them. generate builds a new level that is
+always solvable; the same seed gives the same level, and no seed picks one.";
+
+pub enum RenderFormat {
+    Ascii,
+    Png(PathBuf),
+}
+
+pub enum Command {
+    // No file plays the built-in level
+    Play {
+        file: Option<PathBuf>,
+        level: u32,
+        reverse: bool,
+    },
+    Verify {
+        file: PathBuf,
+        level: u32,
+        solution: String,
+        reverse: bool,
+    },
+    Solve {
+        file: PathBuf,
+        level: u32,
+    },
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+    },
+    Render {
+        file: PathBuf,
+        level: u32,
+        format: RenderFormat,
+    },
+    Stats {
+        file: PathBuf,
+    },
+    Generate {
+        output: PathBuf,
+        options: GeneratorOptions,
+    },
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut level = 1;
+    let mut format = None;
+    let mut reverse = false;
+    let mut options = GeneratorOptions {
+        seed: time_seed(),
+        ..GeneratorOptions::default()
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => {
+                level = args
+                    .next()
+                    .and_then(|number| number.parse().ok())
+                    .filter(|number| *number > 0)
+                    .ok_or("--level needs a level number, counting from 1")?;
+            }
+            "--ascii" => format = Some(RenderFormat::Ascii),
+            "--reverse" => reverse = true,
+            "--seed" => {
+                options.seed = args
+                    .next()
+                    .and_then(|seed| seed.parse().ok())
+                    .ok_or("--seed needs a number")?;
+            }
+            "--size" => {
+                let (width, height) = args
+                    .next()
+                    .and_then(|size| size.split_once('x'))
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .ok_or("--size needs a width and height, like 10x8")?;
+                options.width = width;
+                options.height = height;
+            }
+            "--boxes" => {
+                options.boxes = args
+                    .next()
+                    .and_then(|boxes| boxes.parse().ok())
+                    .ok_or("--boxes needs a number")?;
+            }
+            "--difficulty" => {
+                options.difficulty = args
+                    .next()
+                    .and_then(|difficulty| difficulty.parse().ok())
+                    .filter(|difficulty| *difficulty > 0)
+                    .ok_or("--difficulty needs a number, from 1 up")?;
+            }
+            "--png" => {
+                let output = args.next().ok_or("--png needs an output file")?;
+                format = Some(RenderFormat::Png(PathBuf::from(output)));
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let file = |index: usize| {
+        positional
+            .get(index)
+            .map(PathBuf::from)
+            .ok_or_else(|| format!("{} needs more arguments", positional[0]))
+    };
+
+    let command = match positional.first().copied() {
+        None => Command::Play {
+            file: None,
+            level,
+            reverse,
+        },
+        Some("play") => Command::Play {
+            file: Some(file(1)?),
+            level,
+            reverse,
+        },
+        Some("verify") => Command::Verify {
+            file: file(1)?,
+            level,
+            solution: file(2)?.to_string_lossy().to_string(),
+            reverse,
+        },
+        Some("solve") => Command::Solve {
+            file: file(1)?,
+            level,
+        },
+        Some("convert") => Command::Convert {
+            input: file(1)?,
+            output: file(2)?,
+        },
+        Some("render") => Command::Render {
+            file: file(1)?,
+            level,
+            format: format.ok_or("render needs --ascii or --png <out.png>")?,
+        },
+        Some("stats") => Command::Stats { file: file(1)? },
+        Some("generate") => Command::Generate {
+            output: file(1)?,
+            options,
+        },
+        Some(command) => return Err(format!("Unknown command {}", command)),
+    };
+
+    Ok(command)
+}
+
+// Run one of the commands that don't need a window
+pub fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Play { .. } => Err("play needs a window".to_string()),
+        Command::Verify {
+            file,
+            level,
+            solution,
+            reverse,
+        } => verify(&read_level(&file, level)?, &solution, reverse),
+        Command::Solve { file, level } => {
+            let world = headless_world(&read_level(&file, level)?);
+            let puzzle = Puzzle::from_world(&world).ok_or("The level has no player")?;
+            let lurd = solve(&puzzle).ok_or("No solution found")?;
+            println!("{}", lurd);
+            Ok(())
+        }
+        Command::Convert { input, output } => convert(&input, &output),
+        Command::Render {
+            file,
+            level,
+            format,
+        } => {
+            let world = headless_world(&read_level(&file, level)?);
+            match format {
+                RenderFormat::Ascii => {
+                    println!("{}", write_xsb(&grid_from_world(&world)));
+                    Ok(())
+                }
+                RenderFormat::Png(output) => render_png(&world, &output),
+            }
+        }
+        Command::Stats { file } => stats(&file),
+        Command::Generate { output, options } => {
+            let grid = generate(&options)?;
+            fs::write(
+                &output,
+                write_collection(Format::from_path(&output), &[grid]),
+            )
+            .map_err(|error| format!("Couldn't write {}: {}", output.display(), error))?;
+            println!("Generated {} with seed {}", output.display(), options.seed);
+            Ok(())
+        }
+    }
+}
+
+// Without --seed each run gives a new level, and the seed is printed so it can be made again
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+// A world with the level loaded and every resource the game systems read, but no context
+pub fn headless_world(level: &Level) -> World {
+    let mut world = World::new();
+    register_components(&mut world);
+    register_resources(&mut world);
+    initialize_level(&mut world, level);
+    world
+}
+
+// Apply one action the way a game frame would, minus the sounds and drawing
+fn apply(world: &mut World, action: Action) {
+    world
+        .write_resource::<InputQueue>()
+        .actions
+        .push_back(action);
+
+    InputSystem {}.run_now(world);
+    TriggerSystem {}.run_now(world);
+    world.write_resource::<EventQueue>().events.clear();
+    GameStateSystem {}.run_now(world);
+    world.maintain();
+}
+
+// The solution is either a LURD string or a file holding one
+pub fn verify(level: &Level, solution: &str, reverse: bool) -> Result<(), String> {
+    let lurd = fs::read_to_string(solution).unwrap_or_else(|_| solution.to_string());
+    let steps = parse_lurd(&lurd).map_err(|c| format!("'{}' is not a LURD move", c))?;
+    let pushes = lurd
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(char::is_uppercase);
+
+    let mut world = headless_world(level);
+    if reverse {
+        reverse_level(&mut world)?;
+    }
+    for (index, (direction, push)) in steps.into_iter().zip(pushes).enumerate() {
+        let before = gameplay_counts(&world);
+        apply(&mut world, Action::Move(direction));
+        let after = gameplay_counts(&world);
+
+        if after.0 == before.0 {
+            return Err(format!("Move {} is blocked", index + 1));
+        }
+        if (after.1 > before.1) != push {
+            return Err(format!(
+                "Move {} {} a box but is written as a {}",
+                index + 1,
+                if push { "doesn't push" } else { "pushes" },
+                if push { "push" } else { "walk" }
+            ));
+        }
+    }
+
+    let gameplay = world.read_resource::<Gameplay>();
+    if gameplay.state != GameplayState::Won {
+        return Err("The solution doesn't solve the level".to_string());
+    }
+
+    println!(
+        "Solved in {} moves, {} pushes",
+        gameplay.moves_count, gameplay.pushes_count
+    );
+    Ok(())
+}
+
+fn gameplay_counts(world: &World) -> (u32, u32) {
+    let gameplay = world.read_resource::<Gameplay>();
+    (gameplay.moves_count, gameplay.pushes_count)
+}
+
+// Each level goes through a world and back out, so the output is what the game would load
+fn convert(input: &Path, output: &Path) -> Result<(), String> {
+    let grids = read_collection(input)?
+        .iter()
+        .map(|level| grid_from_world(&headless_world(level)))
+        .collect::<Vec<Grid>>();
+
+    fs::write(output, write_collection(Format::from_path(output), &grids))
+        .map_err(|error| format!("Couldn't write {}: {}", output.display(), error))
+}
+
+// Composite each entity's first sprite, in the same z order the game draws them
+fn render_png(world: &World, output: &Path) -> Result<(), String> {
+    const TILE_SIZE: u32 = 32;
+
+    let positions = world.read_storage::<Position>();
+    let renderables = world.read_storage::<Renderable>();
+
+    let resources = resources_dir();
+    let mut sprites = (&positions, &renderables).join().collect::<Vec<_>>();
+    sprites.sort_by_key(|t| t.0.z);
+
+    let topology = *world.read_resource::<Topology>();
+    let (width, height) = sprites.iter().fold((0, 0), |(width, height), t| {
+        (width.max(t.0.x + 1), height.max(t.0.y + 1))
+    });
+    let (width, height) = topology.extent(width, height, TILE_SIZE as f32);
+    let mut canvas = image::RgbaImage::new(width as u32, height as u32);
+
+    for (position, renderable) in sprites {
+        let path = resources.join(renderable.path(0).trim_start_matches('/'));
+        let mut sprite = image::open(&path)
+            .map_err(|error| format!("Couldn't load {}: {}", path.display(), error))?
+            .to_rgba();
+
+        if let Some((r, g, b)) = renderable.tint {
+            for pixel in sprite.pixels_mut() {
+                pixel[0] = (pixel[0] as f32 * r) as u8;
+                pixel[1] = (pixel[1] as f32 * g) as u8;
+                pixel[2] = (pixel[2] as f32 * b) as u8;
+            }
+        }
+
+        // Triangle cells draw their sprites smaller, the same as in the game
+        let (x, y, scale) = topology.layout((position.x, position.y), TILE_SIZE as f32);
+        if scale != 1.0 {
+            let size = (TILE_SIZE as f32 * scale) as u32;
+            sprite = image::imageops::resize(&sprite, size, size, image::FilterType::Triangle);
+        }
+
+        image::imageops::overlay(&mut canvas, &sprite, x as u32, y as u32);
+    }
+
+    canvas
+        .save(output)
+        .map_err(|error| format!("Couldn't write {}: {}", output.display(), error))
+}
+
+// The resources shipped next to the executable, or else the ones in the source tree it was built from,
+// so rendering works from any directory
+fn resources_dir() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("resources")))
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("resources"))
+}
+
+fn stats(file: &Path) -> Result<(), String> {
+    let levels = read_collection(file)?;
+    let mut total_boxes = 0;
+
+    for level in levels.iter() {
+        let world = headless_world(level);
+        let positions = world.read_storage::<Position>();
+        let (width, height) = positions.join().fold((0, 0), |(width, height), position| {
+            (width.max(position.x + 1), height.max(position.y + 1))
+        });
+        let boxes = world.read_storage::<Box>().join().count();
+        let box_spots = world.read_storage::<BoxSpot>().join().count();
+        total_boxes += boxes;
+
+        println!(
+            "{:>3}  {:<24} {}x{}, {} boxes, {} goals",
+            level.number, level.grid.meta.title, width, height, boxes, box_spots
+        );
+    }
+
+    println!("{} levels, {} boxes in total", levels.len(), total_boxes);
+    Ok(())
+}
@@ -1,20 +1,108 @@
-use specs::{Component, NullStorage, VecStorage, World, WorldExt};
+use crate::actions::Direction;
+use specs::{Component, Entity, NullStorage, VecStorage, World, WorldExt};
 use std::fmt;
 use std::fmt::Display;
 
-#[derive(PartialEq)]
+const COLOUR_NAMES: [&str; 8] = [
+    "blue", "red", "green", "yellow", "purple", "orange", "cyan", "pink",
+];
+
+// Tints for the white box sprites; blue and red match the sprites they replaced
+const PALETTE: [(f32, f32, f32); 8] = [
+    (0.01, 0.43, 0.62),
+    (0.62, 0.01, 0.15),
+    (0.13, 0.55, 0.13),
+    (0.85, 0.65, 0.05),
+    (0.45, 0.2, 0.6),
+    (0.9, 0.45, 0.05),
+    (0.05, 0.65, 0.7),
+    (0.9, 0.4, 0.6),
+];
+
+// Which boxes go on which spots; the number is also the box's Sokoban+ number,
+// and the first few groups have colour names while the rest go by number
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum BoxColour {
-    Blue,
-    Red,
+    // A plain box fits on any spot, and a plain spot takes any box, as in classic Sokoban
+    Plain,
+    Group(u8),
+}
+
+impl BoxColour {
+    pub const BLUE: BoxColour = BoxColour::Group(1);
+    pub const RED: BoxColour = BoxColour::Group(2);
+
+    pub fn from_name(name: &str) -> Option<BoxColour> {
+        if name == "plain" {
+            return Some(BoxColour::Plain);
+        }
+        match COLOUR_NAMES.iter().position(|colour| *colour == name) {
+            Some(index) => Some(BoxColour::Group(index as u8 + 1)),
+            None => name.parse().ok().map(BoxColour::from_number),
+        }
+    }
+
+    // Sokoban+ numbering, where 0 is a plain box or spot
+    pub fn from_number(number: u8) -> BoxColour {
+        match number {
+            0 => BoxColour::Plain,
+            number => BoxColour::Group(number),
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        match self {
+            BoxColour::Plain => 0,
+            BoxColour::Group(number) => number,
+        }
+    }
+
+    // Whether a box of this colour counts as placed on a spot of `spot`'s colour
+    pub fn fits(self, spot: BoxColour) -> bool {
+        self == BoxColour::Plain || spot == BoxColour::Plain || self == spot
+    }
+
+    // Plain boxes keep their own sprite; groups past the palette are spread around the colour wheel
+    pub fn tint(self) -> Option<(f32, f32, f32)> {
+        let number = match self {
+            BoxColour::Plain => return None,
+            BoxColour::Group(number) => number,
+        };
+        if let Some(tint) = (number as usize)
+            .checked_sub(1)
+            .and_then(|i| PALETTE.get(i))
+        {
+            return Some(*tint);
+        }
+
+        let hue = (number as f32 * 137.5) % 360.0 / 60.0;
+        let (value, chroma) = (0.75, 0.6);
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = value - chroma;
+        Some((r + m, g + m, b + m))
+    }
 }
 
 impl Display for BoxColour {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str(match self {
-            Self::Blue => "blue",
-            Self::Red => "red",
-        })?;
-        Ok(())
+        match self {
+            BoxColour::Plain => fmt.write_str("plain"),
+            BoxColour::Group(number) => match (*number as usize)
+                .checked_sub(1)
+                .and_then(|i| COLOUR_NAMES.get(i))
+            {
+                Some(name) => fmt.write_str(name),
+                None => write!(fmt, "{}", number),
+            },
+        }
     }
 }
 
@@ -35,15 +123,24 @@ pub struct Position {
 #[storage(VecStorage)]
 pub struct Renderable {
     paths: Vec<String>,
+    // Multiplied into the sprite's colours, so one white sprite can be drawn in any colour
+    pub tint: Option<(f32, f32, f32)>,
 }
 
 impl Renderable {
     pub fn new_static(path: String) -> Self {
-        Renderable { paths: vec![path] }
+        Renderable {
+            paths: vec![path],
+            tint: None,
+        }
     }
 
     pub fn new_animated(paths: Vec<String>) -> Self {
-        Renderable { paths }
+        Renderable { paths, tint: None }
+    }
+
+    pub fn with_tint(self, tint: Option<(f32, f32, f32)>) -> Self {
+        Renderable { tint, ..self }
     }
 
     pub fn kind(&self) -> RenderableType {
@@ -71,6 +168,124 @@ pub struct Immovable;
 #[storage(VecStorage)]
 pub struct Wall {}
 
+// Floor that anything moving onto keeps sliding across
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Ice;
+
+// A pit that blocks the player until a box is pushed in to fill it
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Hole;
+
+// One of a pair of pads sharing a link; the player stepping onto one comes out of the other,
+// and so does a box if the pad carries boxes
+#[derive(Component, Clone, Copy, PartialEq)]
+#[storage(VecStorage)]
+pub struct Teleporter {
+    pub link: u8,
+    pub boxes: bool,
+}
+
+// A plate that holds the doors sharing its link open while a box sits on it
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Switch {
+    pub link: u8,
+}
+
+// What opens a door: a box on every switch with its link, or the player holding its key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorKind {
+    Switch,
+    Key,
+}
+
+// Immovable while closed
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Door {
+    pub link: u8,
+    pub kind: DoorKind,
+}
+
+// Picked up by walking onto it, after which it is off the board and opens every door with its link
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Key {
+    pub link: u8,
+    // The player that picked it up, so a saved level can put it back under them
+    pub holder: Option<Entity>,
+}
+
+// What a one-way tile holds to its direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    Everything,
+    Player,
+    Boxes,
+}
+
+// Can only be entered moving the way it points, and not left against it
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+#[storage(VecStorage)]
+pub struct OneWay {
+    pub direction: Direction,
+    pub traffic: Traffic,
+}
+
+impl OneWay {
+    // Written as an arrow, `>` `<` `^` or `v`, or for the hex diagonals one of the LURD letters `q` `e` `z`
+    // or `c`, then `p` if it only holds the player or `b` for boxes
+    pub fn from_token(token: &str) -> Option<OneWay> {
+        let mut chars = token.chars();
+        let direction = match chars.next()? {
+            '^' => Direction::Up,
+            'v' => Direction::Down,
+            '<' => Direction::Left,
+            '>' => Direction::Right,
+            'q' => Direction::UpLeft,
+            'e' => Direction::UpRight,
+            'z' => Direction::DownLeft,
+            'c' => Direction::DownRight,
+            _ => return None,
+        };
+        let traffic = match chars.as_str() {
+            "" => Traffic::Everything,
+            "p" => Traffic::Player,
+            "b" => Traffic::Boxes,
+            _ => return None,
+        };
+
+        Some(OneWay { direction, traffic })
+    }
+
+    pub fn token(self) -> String {
+        let arrow = match self.direction {
+            Direction::Up => '^',
+            Direction::Down => 'v',
+            Direction::Left => '<',
+            Direction::Right => '>',
+            direction => direction.to_lurd(false),
+        };
+        let suffix = match self.traffic {
+            Traffic::Everything => "",
+            Traffic::Player => "p",
+            Traffic::Boxes => "b",
+        };
+
+        format!("{}{}", arrow, suffix)
+    }
+
+    pub fn applies_to(self, is_box: bool) -> bool {
+        match self.traffic {
+            Traffic::Everything => true,
+            Traffic::Player => !is_box,
+            Traffic::Boxes => is_box,
+        }
+    }
+}
+
 #[derive(Component)]
 #[storage(VecStorage)]
 pub struct Player {}
@@ -93,6 +308,13 @@ pub fn register_components(world: &mut World) {
     world.register::<Movable>();
     world.register::<Immovable>();
     world.register::<Wall>();
+    world.register::<Ice>();
+    world.register::<Hole>();
+    world.register::<Teleporter>();
+    world.register::<OneWay>();
+    world.register::<Switch>();
+    world.register::<Door>();
+    world.register::<Key>();
     world.register::<Player>();
     world.register::<Box>();
     world.register::<BoxSpot>();
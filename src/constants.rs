@@ -1,3 +1,10 @@
+use std::time::Duration;
+
 pub const TILE_WIDTH: f32 = 32.0;
-pub const MAP_WIDTH: u8 = 8;
-pub const MAP_HEIGHT: u8 = 9;
+pub const STICK_DEADZONE: f32 = 0.5;
+pub const REPEAT_DELAY: Duration = Duration::from_millis(300);
+pub const REPEAT_INTERVAL: Duration = Duration::from_millis(150);
+pub const MOVE_TWEEN_DURATION: Duration = Duration::from_millis(80);
+pub const MAX_QUEUED_ACTIONS: usize = 8;
+pub const WALK_STEP_INTERVAL: Duration = Duration::from_millis(100);
+pub const SOLUTION_STEP_INTERVAL: Duration = Duration::from_millis(200);
@@ -1,3 +1,4 @@
+use crate::actions::Direction;
 use crate::components::*;
 use specs::{Builder, World, WorldExt};
 
@@ -19,14 +20,120 @@ pub fn create_floor(world: &mut World, position: Position) {
         .build();
 }
 
+pub fn create_ice(world: &mut World, position: Position) {
+    world
+        .create_entity()
+        .with(Position { z: 6, ..position })
+        .with(Renderable::new_static("/images/ice.png".to_string()))
+        .with(Ice)
+        .build();
+}
+
+pub fn create_hole(world: &mut World, position: Position) {
+    world
+        .create_entity()
+        .with(Position { z: 6, ..position })
+        .with(Renderable::new_static("/images/hole.png".to_string()))
+        .with(Hole)
+        .with(Immovable)
+        .build();
+}
+
+// Pads borrow the box palette, so each pair is told apart by its colour
+pub fn create_teleporter(world: &mut World, position: Position, teleporter: Teleporter) {
+    world
+        .create_entity()
+        .with(Position { z: 7, ..position })
+        .with(
+            Renderable::new_static("/images/teleporter.png".to_string())
+                .with_tint(BoxColour::Group(teleporter.link).tint()),
+        )
+        .with(teleporter)
+        .build();
+}
+
+pub fn create_switch(world: &mut World, position: Position, link: u8) {
+    world
+        .create_entity()
+        .with(Position { z: 7, ..position })
+        .with(
+            Renderable::new_static("/images/switch.png".to_string())
+                .with_tint(BoxColour::Group(link).tint()),
+        )
+        .with(Switch { link })
+        .build();
+}
+
+// Doors start closed, and the trigger system opens them once their switches or key allow
+pub fn create_door(world: &mut World, position: Position, link: u8, kind: DoorKind) {
+    world
+        .create_entity()
+        .with(Position { z: 8, ..position })
+        .with(door_renderable(link, kind, false))
+        .with(Door { link, kind })
+        .with(Immovable)
+        .build();
+}
+
+pub fn door_renderable(link: u8, kind: DoorKind, open: bool) -> Renderable {
+    let path = match (kind, open) {
+        (_, true) => "/images/door_open.png",
+        (DoorKind::Switch, false) => "/images/door_closed.png",
+        (DoorKind::Key, false) => "/images/door_locked.png",
+    };
+    Renderable::new_static(path.to_string()).with_tint(BoxColour::Group(link).tint())
+}
+
+pub fn create_key(world: &mut World, position: Position, link: u8) {
+    world
+        .create_entity()
+        .with(Position { z: 9, ..position })
+        .with(
+            Renderable::new_static("/images/key.png".to_string())
+                .with_tint(BoxColour::Group(link).tint()),
+        )
+        .with(Key { link, holder: None })
+        .build();
+}
+
+// One arrow sprite per direction, tinted by what the tile holds to it
+pub fn create_one_way(world: &mut World, position: Position, one_way: OneWay) {
+    let name = match one_way.direction {
+        Direction::Up => "up",
+        Direction::Down => "down",
+        Direction::Left => "left",
+        Direction::Right => "right",
+        Direction::UpLeft => "up_left",
+        Direction::UpRight => "up_right",
+        Direction::DownLeft => "down_left",
+        Direction::DownRight => "down_right",
+    };
+    let tint = match one_way.traffic {
+        Traffic::Everything => None,
+        Traffic::Player => Some((0.3, 0.75, 0.3)),
+        Traffic::Boxes => Some((0.85, 0.55, 0.2)),
+    };
+
+    world
+        .create_entity()
+        .with(Position { z: 7, ..position })
+        .with(Renderable::new_static(format!("/images/one_way_{}.png", name)).with_tint(tint))
+        .with(one_way)
+        .build();
+}
+
 pub fn create_box(world: &mut World, position: Position, colour: BoxColour) {
     world
         .create_entity()
         .with(Position { z: 10, ..position })
-        .with(Renderable::new_animated(vec![
-            format!("/images/box_{}_1.png", colour),
-            format!("/images/box_{}_2.png", colour),
-        ]))
+        .with(match colour {
+            BoxColour::Plain => Renderable::new_static("/images/box.png".to_string()),
+            _ => Renderable::new_animated(vec![
+                "/images/box_base_1.png".to_string(),
+                "/images/box_base_2.png".to_string(),
+            ])
+            .with_tint(colour.tint()),
+        })
         .with(Box { colour })
         .with(Movable)
         .build();
@@ -36,10 +143,11 @@ pub fn create_box_spot(world: &mut World, position: Position, colour: BoxColour)
     world
         .create_entity()
         .with(Position { z: 9, ..position })
-        .with(Renderable::new_static(format!(
-            "/images/box_spot_{}.png",
-            colour
-        )))
+        .with(match colour {
+            BoxColour::Plain => Renderable::new_static("/images/box_spot.png".to_string()),
+            _ => Renderable::new_static("/images/box_spot_base.png".to_string())
+                .with_tint(colour.tint()),
+        })
         .with(BoxSpot { colour })
         .build();
 }
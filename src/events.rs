@@ -15,4 +15,9 @@ pub enum Event {
     PlayerHitObstacle,
     EntityMoved(EntityMoved),
     BoxPlacedOnSpot(BoxPlacedOnSpot),
+    Teleported,
+    BoxFilledHole,
+    KeyPickedUp,
+    DoorOpened,
+    DoorClosed,
 }
@@ -0,0 +1,1084 @@
+use crate::components::{BoxColour, DoorKind, OneWay, Teleporter};
+use crate::map::{parse_tokens, write_tokens, Cell, Grid, LevelMeta, MAX_GRID_SIZE};
+use crate::topology::Topology;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // The project's own `W . P BB` token grid
+    Tokens,
+    Xsb,
+    // Run-length encoded XSB, one level per line with `|` between rows
+    Rle,
+    Json,
+    // Metadata, a layered grid and an entity list, see `TomlLevel`
+    Toml,
+}
+
+impl Format {
+    // Anything unrecognised is taken to be the project's own format
+    pub fn from_path(path: &Path) -> Format {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("xsb") | Some("sok") => Format::Xsb,
+            Some("rle") => Format::Rle,
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ => Format::Tokens,
+        }
+    }
+}
+
+pub fn parse_collection(format: Format, contents: &str) -> Result<Vec<Grid>, String> {
+    match format {
+        Format::Tokens => split_collection(contents)
+            .into_iter()
+            .map(|(header, rows)| Ok(with_header(parse_tokens(&rows.join("\n"))?, header)))
+            .collect(),
+        Format::Xsb => split_collection(contents)
+            .into_iter()
+            .map(|(header, rows)| {
+                let meta = with_header(Grid::default(), header).meta;
+                number_boxes(parse_xsb(&rows, meta)?)
+            })
+            .collect(),
+        Format::Rle => split_collection(contents)
+            .into_iter()
+            .map(|(header, rows)| {
+                let expanded = rows
+                    .iter()
+                    .map(|row| expand_rle(row))
+                    .collect::<Result<Vec<String>, String>>()?
+                    .join("|");
+                let rows = expanded.split('|').collect::<Vec<&str>>();
+                let meta = with_header(Grid::default(), header).meta;
+                number_boxes(parse_xsb(&rows, meta)?)
+            })
+            .collect(),
+        Format::Json => {
+            let levels: Vec<JsonLevel> = serde_json::from_str(contents)
+                .map_err(|error| format!("Invalid JSON level: {}", error))?;
+            levels.into_iter().map(JsonLevel::into_grid).collect()
+        }
+        Format::Toml => {
+            let collection: TomlCollection = toml::from_str(contents)
+                .map_err(|error| format!("Invalid TOML level: {}", error))?;
+            collection
+                .levels
+                .into_iter()
+                .map(TomlLevel::into_grid)
+                .collect()
+        }
+    }
+}
+
+pub fn write_collection(format: Format, grids: &[Grid]) -> String {
+    if format == Format::Json {
+        let levels = grids.iter().map(JsonLevel::from_grid).collect::<Vec<_>>();
+        return serde_json::to_string_pretty(&levels).expect("Expected levels to serialize") + "\n";
+    }
+    if format == Format::Toml {
+        let collection = TomlCollection {
+            levels: grids.iter().map(TomlLevel::from_grid).collect(),
+        };
+        return toml::to_string_pretty(&collection).expect("Expected levels to serialize");
+    }
+
+    grids
+        .iter()
+        .map(|grid| {
+            let body = match format {
+                Format::Tokens => write_tokens(grid),
+                Format::Xsb => write_xsb(grid) + &box_numbers(grid),
+                _ => compress_rle(&write_xsb(grid).replace('\n', "|")) + &box_numbers(grid),
+            };
+            format!("{}{}\n", write_header(grid), body)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Title and metadata comments, then the rows; levels are separated by blank lines
+type Header = Vec<String>;
+
+// Keys that may head a line without a `;`, as XSB files write them
+const HEADER_KEYS: &[&str] = &[
+    "title",
+    "author",
+    "comment",
+    "difficulty",
+    "par",
+    "par_moves",
+    "hint",
+    "theme",
+    "music",
+    "topology",
+    "boxorder",
+    "goalorder",
+];
+
+fn is_header_line(line: &str) -> bool {
+    line.split_once(':').is_some_and(|(key, _value)| {
+        HEADER_KEYS
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(key.trim()))
+    })
+}
+
+fn split_collection(contents: &str) -> Vec<(Header, Vec<&str>)> {
+    let mut levels = Vec::new();
+    let mut header = Vec::new();
+    let mut rows = Vec::new();
+
+    for line in contents.lines().chain(std::iter::once("")) {
+        if let Some(comment) = line.trim().strip_prefix(';') {
+            header.push(comment.trim().to_string());
+        } else if is_header_line(line) {
+            // A `Title:` or Sokoban+ `Boxorder:` style line
+            header.push(line.trim().to_string());
+        } else if !line.trim().is_empty() {
+            rows.push(line.trim_end());
+        } else if !rows.is_empty() {
+            levels.push((std::mem::take(&mut header), std::mem::take(&mut rows)));
+        }
+    }
+
+    levels
+}
+
+// `; Key: value` comments are metadata, any other comment is the title
+fn with_header(mut grid: Grid, header: Header) -> Grid {
+    for comment in header {
+        match comment.split_once(':') {
+            Some((key, value)) if !key.trim().is_empty() && !key.contains(' ') => {
+                grid.meta.set(key.trim(), value.trim());
+            }
+            _ => grid.meta.title = comment,
+        }
+    }
+    grid
+}
+
+fn write_header(grid: &Grid) -> String {
+    let mut header = String::new();
+    if !grid.meta.title.is_empty() {
+        header += &format!("; {}\n", grid.meta.title);
+    }
+    for (key, value) in grid.meta.entries() {
+        header += &format!("; {}: {}\n", key, value);
+    }
+    header
+}
+
+// XSB has no box colours, so every box and goal starts out plain. Hex levels put a space between
+// cells and shift every other row by one character, as Hexoban files do
+fn parse_xsb(rows: &[&str], meta: LevelMeta) -> Result<Grid, String> {
+    let topology = meta.topology;
+    let mut grid = Grid {
+        meta,
+        ..Grid::default()
+    };
+    let mut players = Vec::new();
+
+    // Whichever way round the file staggers its rows, the first cell's column lines them up
+    let stagger = rows
+        .iter()
+        .enumerate()
+        .find_map(|(y, row)| Some(row.find(|c| c != ' ')? + y % 2))
+        .unwrap_or(0)
+        % 2;
+
+    for (y, row) in rows.iter().enumerate() {
+        for (column, c) in row.chars().enumerate() {
+            let x = if topology == Topology::Hex {
+                match (column + stagger).checked_sub(y % 2) {
+                    Some(offset) if offset % 2 == 0 => offset / 2,
+                    _ if c == ' ' => continue,
+                    _ => return Err(format!("Hex XSB character {} is between cells", c)),
+                }
+            } else {
+                column
+            };
+            let cell = grid.try_cell_mut(x, y)?;
+            match c {
+                ' ' | '-' | '_' => (),
+                '#' => cell.wall = true,
+                '@' | '+' => cell.player = true,
+                '$' | '*' => cell.the_box = Some(BoxColour::Plain),
+                '.' => (),
+                c => return Err(format!("Unrecognised XSB character {}", c)),
+            }
+            if c == '.' || c == '*' || c == '+' {
+                cell.box_spot = Some(BoxColour::Plain);
+            }
+            if c == '@' || c == '+' {
+                players.push((x as u8, y as u8));
+            }
+            // Walls always sit on floor, as they do in the token format
+            cell.floor = c != ' ' && c != '-' && c != '_';
+        }
+    }
+
+    // Spaces are floor inside the walls and nothing outside them, so flood fill from the players
+    if players.is_empty() {
+        return Err("XSB level has no player".to_string());
+    }
+    let mut inside = HashSet::new();
+    let mut frontier = players;
+    while let Some((x, y)) = frontier.pop() {
+        let is_open = grid
+            .rows
+            .get(y as usize)
+            .and_then(|row| row.get(x as usize))
+            .is_some_and(|cell| !cell.wall);
+        if !is_open || !inside.insert((x, y)) {
+            continue;
+        }
+        let neighbours = topology.directions().iter();
+        frontier.extend(neighbours.filter_map(|direction| topology.step((x, y), *direction)));
+    }
+    for (x, y) in inside {
+        grid.rows[y as usize][x as usize].floor = true;
+    }
+
+    Ok(grid)
+}
+
+// Box colours are written separately by `box_numbers`, since XSB only has the one kind of box
+pub fn write_xsb(grid: &Grid) -> String {
+    let is_hex = grid.meta.topology == Topology::Hex;
+
+    grid.rows
+        .iter()
+        .enumerate()
+        .map(|(y, row)| {
+            let cells = row
+                .iter()
+                .map(|cell| match cell {
+                    Cell { wall: true, .. } => '#',
+                    Cell {
+                        player: true,
+                        box_spot: Some(_),
+                        ..
+                    } => '+',
+                    Cell { player: true, .. } => '@',
+                    Cell {
+                        the_box: Some(_),
+                        box_spot: Some(_),
+                        ..
+                    } => '*',
+                    Cell {
+                        the_box: Some(_), ..
+                    } => '$',
+                    Cell {
+                        box_spot: Some(_), ..
+                    } => '.',
+                    Cell { floor: true, .. } => '-',
+                    _ => ' ',
+                })
+                .map(String::from)
+                .collect::<Vec<String>>();
+            let row = if is_hex {
+                let indent = if y % 2 == 1 { " " } else { "" };
+                format!("{}{}", indent, cells.join(" "))
+            } else {
+                cells.concat()
+            };
+            row.trim_end().replace('-', " ")
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Sokoban+ numbers boxes and goals in reading order, with lines like `Boxorder: 1 2 2`
+fn number_boxes(mut grid: Grid) -> Result<Grid, String> {
+    for (key, is_box) in [("boxorder", true), ("goalorder", false)].iter() {
+        let name = grid
+            .meta
+            .extra
+            .keys()
+            .find(|name| name.eq_ignore_ascii_case(key))
+            .cloned();
+        let order = match name.and_then(|name| grid.meta.extra.remove(&name)) {
+            Some(order) => order,
+            None => continue,
+        };
+
+        let numbers = order
+            .split_whitespace()
+            .map(|number| number.parse().map(BoxColour::from_number))
+            .collect::<Result<Vec<BoxColour>, _>>()
+            .map_err(|_| format!("Invalid Sokoban+ order {}", order))?;
+
+        let cells = grid
+            .rows
+            .iter_mut()
+            .flatten()
+            .filter_map(|cell| {
+                if *is_box {
+                    cell.the_box.as_mut()
+                } else {
+                    cell.box_spot.as_mut()
+                }
+            })
+            .collect::<Vec<_>>();
+        if cells.len() != numbers.len() {
+            return Err(format!(
+                "{} numbers {} of {} {}",
+                if *is_box { "Boxorder" } else { "Goalorder" },
+                numbers.len(),
+                cells.len(),
+                if *is_box { "boxes" } else { "goals" }
+            ));
+        }
+        for (colour, number) in cells.into_iter().zip(numbers) {
+            *colour = number;
+        }
+    }
+
+    Ok(grid)
+}
+
+// The Sokoban+ lines for `number_boxes`, left out when every box and goal is plain
+fn box_numbers(grid: &Grid) -> String {
+    let cells = || grid.rows.iter().flatten();
+    let boxes = cells().filter_map(|cell| cell.the_box).collect::<Vec<_>>();
+    let box_spots = cells().filter_map(|cell| cell.box_spot).collect::<Vec<_>>();
+
+    if boxes
+        .iter()
+        .chain(box_spots.iter())
+        .all(|colour| *colour == BoxColour::Plain)
+    {
+        return String::new();
+    }
+
+    let order = |colours: &[BoxColour]| {
+        colours
+            .iter()
+            .map(|colour| colour.number().to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+    format!(
+        "\nBoxorder: {}\nGoalorder: {}",
+        order(&boxes),
+        order(&box_spots)
+    )
+}
+
+// `3#` is three walls and `2(#-)` is `#-#-`; `|` separates rows and is left alone here
+fn expand_rle(line: &str) -> Result<String, String> {
+    let mut chars = line.chars();
+    let expanded = expand_rle_group(&mut chars, false)?;
+    if expanded.len() > MAX_GRID_SIZE * (MAX_GRID_SIZE + 1) {
+        return Err("Run-length encoded level is too big".to_string());
+    }
+    Ok(expanded)
+}
+
+// Expand up to the `)` closing a group, or to the end of the line outside one
+fn expand_rle_group(chars: &mut std::str::Chars, in_group: bool) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut count = String::new();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            count.push(c);
+            continue;
+        }
+        if c == ')' {
+            if !in_group {
+                return Err("Unmatched ) in run-length encoding".to_string());
+            }
+            if !count.is_empty() {
+                return Err(format!("Run length {} has nothing to repeat", count));
+            }
+            return Ok(expanded);
+        }
+
+        let repeat = if count.is_empty() {
+            1
+        } else {
+            count
+                .parse::<usize>()
+                .ok()
+                .filter(|repeat| *repeat <= MAX_GRID_SIZE)
+                .ok_or_else(|| format!("Invalid run length {}", count))?
+        };
+        count.clear();
+
+        let run = if c == '(' {
+            expand_rle_group(chars, true)?
+        } else {
+            c.to_string()
+        };
+        if expanded.len() + run.len() * repeat > MAX_GRID_SIZE * (MAX_GRID_SIZE + 1) {
+            return Err("Run-length encoded level is too big".to_string());
+        }
+        expanded += &run.repeat(repeat);
+    }
+
+    if !count.is_empty() {
+        return Err(format!("Run length {} has nothing to repeat", count));
+    }
+    if in_group {
+        return Err("Unclosed ( in run-length encoding".to_string());
+    }
+    Ok(expanded)
+}
+
+fn compress_rle(line: &str) -> String {
+    let mut compressed = String::new();
+    let mut chars = line
+        .chars()
+        .map(|c| if c == ' ' { '-' } else { c })
+        .peekable();
+
+    while let Some(c) = chars.next() {
+        let mut run = 1;
+        while chars.peek() == Some(&c) {
+            chars.next();
+            run += 1;
+        }
+        if run > 1 {
+            compressed += &run.to_string();
+        }
+        compressed.push(c);
+    }
+
+    compressed
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPiece {
+    x: usize,
+    y: usize,
+    colour: String,
+}
+
+// Anything tied to others by a link number: teleporters, switches, doors and keys
+#[derive(Serialize, Deserialize)]
+struct JsonLinked {
+    x: usize,
+    y: usize,
+    link: u8,
+}
+
+// Pads carry boxes unless `boxes` is false
+#[derive(Serialize, Deserialize)]
+struct JsonTeleporter {
+    x: usize,
+    y: usize,
+    link: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    boxes: Option<bool>,
+}
+
+// The arrow is written as in the token format, e.g. `>` or `^p`
+#[derive(Serialize, Deserialize)]
+struct JsonOneWay {
+    x: usize,
+    y: usize,
+    arrow: String,
+}
+
+// Each layer as a list of cells, with the metadata alongside
+#[derive(Serialize, Deserialize)]
+struct JsonLevel {
+    #[serde(default)]
+    meta: LevelMeta,
+    width: usize,
+    height: usize,
+    // Keyed by the same layer names as the TOML format
+    #[serde(default)]
+    layers: BTreeMap<String, Vec<(usize, usize)>>,
+    // Files from before the metadata block and layers had these instead, and are still read
+    #[serde(default, skip_serializing)]
+    title: Option<String>,
+    #[serde(default, skip_serializing)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default, skip_serializing)]
+    floor: Vec<(usize, usize)>,
+    #[serde(default, skip_serializing)]
+    walls: Vec<(usize, usize)>,
+    players: Vec<(usize, usize)>,
+    boxes: Vec<JsonPiece>,
+    box_spots: Vec<JsonPiece>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    teleporters: Vec<JsonTeleporter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    one_ways: Vec<JsonOneWay>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    switches: Vec<JsonLinked>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    doors: Vec<JsonLinked>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    locked_doors: Vec<JsonLinked>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<JsonLinked>,
+}
+
+impl JsonLevel {
+    fn from_grid(grid: &Grid) -> Self {
+        let cells = || {
+            grid.rows
+                .iter()
+                .enumerate()
+                .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, cell)| (x, y, cell)))
+        };
+        let piece = |x, y, colour: BoxColour| JsonPiece {
+            x,
+            y,
+            colour: colour.to_string(),
+        };
+        let linked = |link: &dyn Fn(&Cell) -> Option<u8>| {
+            cells()
+                .filter_map(|(x, y, cell)| link(cell).map(|link| JsonLinked { x, y, link }))
+                .collect()
+        };
+        let door = |kind: DoorKind| {
+            move |cell: &Cell| cell.door.filter(|door| door.1 == kind).map(|door| door.0)
+        };
+
+        JsonLevel {
+            meta: grid.meta.clone(),
+            title: None,
+            metadata: BTreeMap::new(),
+            floor: Vec::new(),
+            walls: Vec::new(),
+            width: grid.width(),
+            height: grid.height(),
+            layers: LAYERS
+                .iter()
+                .map(|layer| {
+                    let filled = cells()
+                        .filter(|t| (layer.is_filled)(t.2))
+                        .map(|t| (t.0, t.1))
+                        .collect::<Vec<_>>();
+                    (layer.name.to_string(), filled)
+                })
+                .filter(|(_name, filled)| !filled.is_empty())
+                .collect(),
+            players: cells().filter(|t| t.2.player).map(|t| (t.0, t.1)).collect(),
+            boxes: cells()
+                .filter_map(|(x, y, cell)| cell.the_box.map(|colour| piece(x, y, colour)))
+                .collect(),
+            box_spots: cells()
+                .filter_map(|(x, y, cell)| cell.box_spot.map(|colour| piece(x, y, colour)))
+                .collect(),
+            teleporters: cells()
+                .filter_map(|(x, y, cell)| {
+                    cell.teleporter.map(|teleporter| JsonTeleporter {
+                        x,
+                        y,
+                        link: teleporter.link,
+                        boxes: Some(false).filter(|_| !teleporter.boxes),
+                    })
+                })
+                .collect(),
+            one_ways: cells()
+                .filter_map(|(x, y, cell)| {
+                    cell.one_way.map(|one_way| JsonOneWay {
+                        x,
+                        y,
+                        arrow: one_way.token(),
+                    })
+                })
+                .collect(),
+            switches: linked(&|cell| cell.switch),
+            doors: linked(&door(DoorKind::Switch)),
+            locked_doors: linked(&door(DoorKind::Key)),
+            keys: cells()
+                .flat_map(|(x, y, cell)| {
+                    cell.keys
+                        .iter()
+                        .map(move |link| JsonLinked { x, y, link: *link })
+                })
+                .collect(),
+        }
+    }
+
+    fn into_grid(mut self) -> Result<Grid, String> {
+        if let Some(title) = self.title.take() {
+            self.meta.title = title;
+        }
+        for (key, value) in self.metadata.iter() {
+            self.meta.set(key, value);
+        }
+        let floor = std::mem::take(&mut self.floor);
+        self.layers
+            .entry("floor".to_string())
+            .or_default()
+            .extend(floor);
+        let walls = std::mem::take(&mut self.walls);
+        self.layers
+            .entry("walls".to_string())
+            .or_default()
+            .extend(walls);
+
+        if self.width > MAX_GRID_SIZE || self.height > MAX_GRID_SIZE {
+            return Err(format!("A {}x{} level is too big", self.width, self.height));
+        }
+        let mut grid = Grid {
+            meta: self.meta,
+            rows: vec![vec![Cell::default(); self.width]; self.height],
+        };
+
+        let colour = |piece: &JsonPiece| {
+            BoxColour::from_name(&piece.colour)
+                .ok_or_else(|| format!("Unknown box colour {}", piece.colour))
+        };
+
+        for (name, cells) in self.layers.iter() {
+            let layer = find_layer(name)?;
+            for (x, y) in cells {
+                (layer.fill)(json_cell(&mut grid, *x, *y)?);
+            }
+        }
+        for (x, y) in self.players {
+            json_cell(&mut grid, x, y)?.player = true;
+        }
+        for piece in self.boxes.iter() {
+            json_cell(&mut grid, piece.x, piece.y)?.the_box = Some(colour(piece)?);
+        }
+        for piece in self.box_spots.iter() {
+            json_cell(&mut grid, piece.x, piece.y)?.box_spot = Some(colour(piece)?);
+        }
+        for teleporter in self.teleporters {
+            json_cell(&mut grid, teleporter.x, teleporter.y)?.teleporter = Some(Teleporter {
+                link: teleporter.link,
+                boxes: teleporter.boxes.unwrap_or(true),
+            });
+        }
+        for one_way in self.one_ways {
+            json_cell(&mut grid, one_way.x, one_way.y)?.one_way =
+                Some(parse_arrow(&one_way.arrow)?);
+        }
+        for switch in self.switches {
+            json_cell(&mut grid, switch.x, switch.y)?.switch = Some(switch.link);
+        }
+        for door in self.doors {
+            json_cell(&mut grid, door.x, door.y)?.door = Some((door.link, DoorKind::Switch));
+        }
+        for door in self.locked_doors {
+            json_cell(&mut grid, door.x, door.y)?.door = Some((door.link, DoorKind::Key));
+        }
+        for key in self.keys {
+            json_cell(&mut grid, key.x, key.y)?.keys.push(key.link);
+        }
+
+        Ok(grid)
+    }
+}
+
+// JSON levels give their size up front, and nothing may be placed outside it
+fn json_cell(grid: &mut Grid, x: usize, y: usize) -> Result<&mut Cell, String> {
+    grid.rows
+        .get_mut(y)
+        .and_then(|row| row.get_mut(x))
+        .ok_or_else(|| format!("Cell {},{} is outside the level", x, y))
+}
+
+fn parse_arrow(arrow: &str) -> Result<OneWay, String> {
+    OneWay::from_token(arrow).ok_or_else(|| format!("Unknown one-way arrow {}", arrow))
+}
+
+fn find_layer(name: &str) -> Result<&'static Layer, String> {
+    LAYERS
+        .iter()
+        .find(|layer| layer.name == name)
+        .ok_or_else(|| format!("Unknown layer {}", name))
+}
+
+#[derive(Serialize, Deserialize)]
+struct TomlCollection {
+    levels: Vec<TomlLevel>,
+}
+
+// A layer of the TOML format: rows where a space is empty and any other character fills the cell,
+// so a new kind of tile is a new layer rather than a new token
+struct Layer {
+    name: &'static str,
+    symbol: char,
+    is_filled: fn(&Cell) -> bool,
+    fill: fn(&mut Cell),
+}
+
+const LAYERS: &[Layer] = &[
+    Layer {
+        name: "floor",
+        symbol: '.',
+        is_filled: |cell| cell.floor,
+        fill: |cell| cell.floor = true,
+    },
+    Layer {
+        name: "walls",
+        symbol: '#',
+        is_filled: |cell| cell.wall,
+        fill: |cell| cell.wall = true,
+    },
+    Layer {
+        name: "ice",
+        symbol: '~',
+        is_filled: |cell| cell.ice,
+        fill: |cell| cell.ice = true,
+    },
+    Layer {
+        name: "holes",
+        symbol: 'O',
+        is_filled: |cell| cell.hole,
+        fill: |cell| cell.hole = true,
+    },
+];
+
+// Anything that isn't a whole layer of tiles, placed one by one
+#[derive(Serialize, Deserialize)]
+struct TomlEntity {
+    kind: String,
+    x: usize,
+    y: usize,
+    #[serde(default)]
+    colour: Option<String>,
+    // Which pair a teleporter belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<u8>,
+    // Only written for teleporters that leave boxes behind
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    boxes: Option<bool>,
+    // Which way a one-way tile points, as in the token format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arrow: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TomlLevel {
+    #[serde(default)]
+    meta: LevelMeta,
+    layers: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    entities: Vec<TomlEntity>,
+}
+
+impl TomlLevel {
+    fn from_grid(grid: &Grid) -> Self {
+        let layers = LAYERS
+            .iter()
+            .map(|layer| {
+                let rows = grid
+                    .rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|cell| {
+                                if (layer.is_filled)(cell) {
+                                    layer.symbol
+                                } else {
+                                    ' '
+                                }
+                            })
+                            .collect::<String>()
+                            .trim_end()
+                            .to_string()
+                    })
+                    .collect();
+                (layer.name.to_string(), rows)
+            })
+            .collect();
+
+        let mut entities = Vec::new();
+        for (y, row) in grid.rows.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                let entity = |kind: &str| TomlEntity {
+                    kind: kind.to_string(),
+                    x,
+                    y,
+                    colour: None,
+                    link: None,
+                    boxes: None,
+                    arrow: None,
+                };
+                let colour = |colour: BoxColour| {
+                    Some(colour)
+                        .filter(|colour| *colour != BoxColour::Plain)
+                        .map(|colour| colour.to_string())
+                };
+
+                if cell.player {
+                    entities.push(entity("player"));
+                }
+                if let Some(box_colour) = cell.the_box {
+                    entities.push(TomlEntity {
+                        colour: colour(box_colour),
+                        ..entity("box")
+                    });
+                }
+                if let Some(spot_colour) = cell.box_spot {
+                    entities.push(TomlEntity {
+                        colour: colour(spot_colour),
+                        ..entity("box_spot")
+                    });
+                }
+                if let Some(teleporter) = cell.teleporter {
+                    entities.push(TomlEntity {
+                        link: Some(teleporter.link),
+                        boxes: Some(false).filter(|_| !teleporter.boxes),
+                        ..entity("teleporter")
+                    });
+                }
+                if let Some(one_way) = cell.one_way {
+                    entities.push(TomlEntity {
+                        arrow: Some(one_way.token()),
+                        ..entity("one_way")
+                    });
+                }
+                let linked = |kind: &str, link: Option<u8>| {
+                    link.map(|link| TomlEntity {
+                        link: Some(link),
+                        ..entity(kind)
+                    })
+                };
+                entities.extend(linked("switch", cell.switch));
+                entities.extend(linked(
+                    "door",
+                    cell.door
+                        .filter(|door| door.1 == DoorKind::Switch)
+                        .map(|door| door.0),
+                ));
+                entities.extend(linked(
+                    "locked_door",
+                    cell.door
+                        .filter(|door| door.1 == DoorKind::Key)
+                        .map(|door| door.0),
+                ));
+                for link in cell.keys.iter().copied() {
+                    entities.extend(linked("key", Some(link)));
+                }
+            }
+        }
+
+        TomlLevel {
+            meta: grid.meta.clone(),
+            layers,
+            entities,
+        }
+    }
+
+    fn into_grid(self) -> Result<Grid, String> {
+        let mut grid = Grid {
+            meta: self.meta,
+            ..Grid::default()
+        };
+
+        for (name, rows) in self.layers.iter() {
+            let layer = find_layer(name)?;
+
+            for (y, row) in rows.iter().enumerate() {
+                for (x, c) in row.chars().enumerate() {
+                    if c != ' ' {
+                        (layer.fill)(grid.try_cell_mut(x, y)?);
+                    }
+                }
+            }
+        }
+
+        let link = |entity: &TomlEntity| {
+            entity
+                .link
+                .ok_or_else(|| format!("A {} needs a link", entity.kind))
+        };
+
+        for entity in self.entities {
+            let colour = match entity.colour.as_deref() {
+                Some(name) => BoxColour::from_name(name)
+                    .ok_or_else(|| format!("Unknown box colour {}", name))?,
+                None => BoxColour::Plain,
+            };
+
+            let cell = grid.try_cell_mut(entity.x, entity.y)?;
+            match entity.kind.as_str() {
+                "player" => cell.player = true,
+                "box" => cell.the_box = Some(colour),
+                "box_spot" => cell.box_spot = Some(colour),
+                "teleporter" => {
+                    cell.teleporter = Some(Teleporter {
+                        link: link(&entity)?,
+                        boxes: entity.boxes.unwrap_or(true),
+                    })
+                }
+                "switch" => cell.switch = Some(link(&entity)?),
+                "door" => cell.door = Some((link(&entity)?, DoorKind::Switch)),
+                "locked_door" => cell.door = Some((link(&entity)?, DoorKind::Key)),
+                "key" => cell.keys.push(link(&entity)?),
+                "one_way" => {
+                    let arrow = entity.arrow.ok_or("A one-way tile needs an arrow")?;
+                    cell.one_way = Some(parse_arrow(&arrow)?)
+                }
+                kind => return Err(format!("Unknown entity kind {}", kind)),
+            }
+        }
+
+        Ok(grid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: &str = "; Plain
+; Author: someone
+W W W W W W
+W P . B S W
+W . B S . W
+W W W W W W
+";
+
+    const NUMBERED: &str = "; Numbered
+W W W W W W W
+W P B1 S1 . . W
+W . B2 . S2 . W
+W W W W W W W
+";
+
+    const VARIANTS: &str = "; Variants
+; Difficulty: 3
+W W W W W W W W
+W P BB BS I . T1 W
+W RB . RS H T3p T3p W
+W > X1 D1 K2 L2 T1 W
+W W W W W W W W
+";
+
+    fn first_grid(format: Format, contents: &str) -> Grid {
+        parse_collection(format, contents)
+            .expect("Expected the level to parse")
+            .remove(0)
+    }
+
+    fn assert_round_trip(format: Format, tokens: &str) {
+        let grid = first_grid(Format::Tokens, tokens);
+        let written = write_collection(format, std::slice::from_ref(&grid));
+        let read = first_grid(format, &written);
+
+        assert_eq!(write_tokens(&read), write_tokens(&grid), "{}", written);
+        assert_eq!(read.meta.title, grid.meta.title);
+        assert_eq!(read.meta.entries(), grid.meta.entries());
+    }
+
+    #[test]
+    fn xsb_round_trips() {
+        assert_round_trip(Format::Xsb, PLAIN);
+        assert_round_trip(Format::Xsb, NUMBERED);
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        assert_round_trip(Format::Rle, PLAIN);
+        assert_round_trip(Format::Rle, NUMBERED);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        assert_round_trip(Format::Json, PLAIN);
+        assert_round_trip(Format::Json, VARIANTS);
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        assert_round_trip(Format::Toml, PLAIN);
+        assert_round_trip(Format::Toml, VARIANTS);
+    }
+
+    #[test]
+    fn json_reads_the_older_title_and_metadata_keys() {
+        let level = r#"[{"title": "Old", "metadata": {"Author": "someone"},
+            "width": 3, "height": 1, "floor": [[0, 0], [1, 0], [2, 0]], "walls": [[0, 0], [2, 0]],
+            "players": [[1, 0]], "boxes": [], "box_spots": []}]"#;
+        let grid = first_grid(Format::Json, level);
+
+        assert_eq!(grid.meta.title, "Old");
+        assert_eq!(grid.meta.author.as_deref(), Some("someone"));
+        assert_eq!(write_tokens(&grid), "W P W");
+    }
+
+    #[test]
+    fn only_known_keys_start_a_header_line() {
+        assert!(is_header_line("Title: First"));
+        assert!(is_header_line("boxorder: 1 2"));
+        assert!(!is_header_line("Chapter 1: The Start"));
+        assert!(!is_header_line("#@$.#"));
+
+        let levels = "; Chapter 1: The Start
+#####
+#@$.#
+#####
+Title: First
+";
+        let grid = first_grid(Format::Xsb, levels);
+        assert_eq!(grid.meta.title, "First");
+    }
+
+    #[test]
+    fn sokoban_plus_orders_must_match_the_boxes() {
+        let level = "#####\n#@$.#\n#####\nBoxorder: 1 2\nGoalorder: 1\n";
+        assert!(parse_collection(Format::Xsb, level).is_err());
+    }
+
+    #[test]
+    fn rle_expands_runs_and_groups() {
+        assert_eq!(expand_rle("3#-@"), Ok("###-@".to_string()));
+        assert_eq!(expand_rle("2(#-)|#"), Ok("#-#-|#".to_string()));
+        assert_eq!(expand_rle("2(#2(-$))"), Ok("#-$-$#-$-$".to_string()));
+    }
+
+    #[test]
+    fn rle_rejects_broken_runs() {
+        assert!(expand_rle("#3").is_err());
+        assert!(expand_rle("99999999#").is_err());
+        assert!(expand_rle("2(#").is_err());
+        assert!(expand_rle("#)").is_err());
+        assert!(expand_rle("250(250(3(#)))").is_err());
+    }
+
+    #[test]
+    fn json_rejects_cells_outside_the_level() {
+        let level = r#"[{"width": 2, "height": 2, "layers": {}, "players": [[5, 0]],
+            "boxes": [], "box_spots": []}]"#;
+        assert!(parse_collection(Format::Json, level).is_err());
+
+        let level = r#"[{"width": 99999999, "height": 2, "layers": {}, "players": [],
+            "boxes": [], "box_spots": []}]"#;
+        assert!(parse_collection(Format::Json, level).is_err());
+
+        let level = r#"[{"width": 256, "height": 2, "layers": {}, "players": [[255, 0]],
+            "boxes": [], "box_spots": []}]"#;
+        assert!(parse_collection(Format::Json, level).is_err());
+    }
+
+    #[test]
+    fn cells_past_the_biggest_level_are_rejected() {
+        let wide = vec!["W"; 256].join(" ");
+        assert!(parse_collection(Format::Tokens, &format!("P {}", wide)).is_err());
+        assert!(parse_collection(Format::Xsb, &format!("@{}", "#".repeat(256))).is_err());
+
+        for x in [1, 300, 3_000_000_000usize] {
+            let level = format!(
+                "[[levels]]\n[levels.layers]\nfloor = [\".\"]\n\
+                 [[levels.entities]]\nkind = \"player\"\nx = {}\ny = 0\n",
+                x
+            );
+            assert_eq!(
+                parse_collection(Format::Toml, &level).is_ok(),
+                x == 1,
+                "{}",
+                x
+            );
+        }
+    }
+}
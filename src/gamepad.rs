@@ -0,0 +1,73 @@
+use crate::actions::{Action, Direction};
+use crate::constants::STICK_DEADZONE;
+use ggez::event::{Axis, Button};
+
+#[derive(Default)]
+pub struct GamepadState {
+    pub stick_x: f32,
+    pub stick_y: f32,
+    pub dpad: Option<Direction>,
+}
+
+impl GamepadState {
+    // The d-pad wins over the stick when both are held
+    pub fn held_direction(&self) -> Option<Direction> {
+        self.dpad.or_else(|| self.stick_direction())
+    }
+
+    // Only the dominant axis counts, and only once it leaves the deadzone
+    pub fn stick_direction(&self) -> Option<Direction> {
+        let (x, y) = (self.stick_x, self.stick_y);
+
+        if x.abs().max(y.abs()) < STICK_DEADZONE {
+            None
+        } else if x.abs() > y.abs() {
+            Some(if x > 0.0 {
+                Direction::Right
+            } else {
+                Direction::Left
+            })
+        } else {
+            // Stick Y points up, screen Y points down
+            Some(if y > 0.0 {
+                Direction::Up
+            } else {
+                Direction::Down
+            })
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: f32) {
+        match axis {
+            Axis::LeftStickX => self.stick_x = value,
+            Axis::LeftStickY => self.stick_y = value,
+            _ => (),
+        }
+    }
+}
+
+pub fn dpad_direction(button: Button) -> Option<Direction> {
+    match button {
+        Button::DPadUp => Some(Direction::Up),
+        Button::DPadDown => Some(Direction::Down),
+        Button::DPadLeft => Some(Direction::Left),
+        Button::DPadRight => Some(Direction::Right),
+        _ => None,
+    }
+}
+
+pub fn action_for_button(button: Button) -> Option<Action> {
+    if let Some(direction) = dpad_direction(button) {
+        return Some(Action::Move(direction));
+    }
+
+    match button {
+        Button::West => Some(Action::Undo),
+        Button::North => Some(Action::Redo),
+        Button::East => Some(Action::Hint),
+        Button::Select => Some(Action::Restart),
+        Button::Start => Some(Action::Menu),
+        Button::RightTrigger => Some(Action::SwitchPlayer),
+        _ => None,
+    }
+}
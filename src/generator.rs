@@ -0,0 +1,281 @@
+use crate::actions::Direction;
+use crate::components::BoxColour;
+use crate::map::Grid;
+use crate::topology::Topology;
+use std::collections::HashSet;
+
+// Walks tried per level, keeping whichever lands closest to the difficulty asked for
+const ATTEMPTS: usize = 64;
+
+type Cell = (u8, u8);
+
+pub struct GeneratorOptions {
+    pub width: u8,
+    pub height: u8,
+    pub boxes: u8,
+    // From 1 up, roughly how many cells each box ends up from its goal
+    pub difficulty: u32,
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            width: 10,
+            height: 8,
+            boxes: 3,
+            difficulty: 3,
+            seed: 0,
+        }
+    }
+}
+
+// SplitMix64, small and the same everywhere, so a seed always gives back the same level
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+// Where the boxes and player end up after walking back from a solved level
+struct Walk {
+    boxes: Vec<Cell>,
+    player: Cell,
+    score: u32,
+    // Boxes still sitting on a goal, which make for a duller level
+    left_home: usize,
+}
+
+// Carve a room, put the boxes on goals and pull them away from there. Every pull played
+// backwards is a push, so the level is solvable by undoing the walk
+pub fn generate(options: &GeneratorOptions) -> Result<Grid, String> {
+    if options.width < 5 || options.height < 5 {
+        return Err("Levels need to be at least 5x5".to_string());
+    }
+    if options.boxes == 0 {
+        return Err("Levels need at least one box".to_string());
+    }
+
+    let mut random = Random(options.seed);
+    let floor = carve_room(&mut random, options);
+    let mut cells = floor.iter().copied().collect::<Vec<Cell>>();
+    cells.sort_unstable();
+    if cells.len() < options.boxes as usize * 3 + 2 {
+        return Err(format!(
+            "A {}x{} room is too small for {} boxes",
+            options.width, options.height, options.boxes
+        ));
+    }
+
+    let mut goals = Vec::new();
+    while goals.len() < options.boxes as usize {
+        let cell = random.pick(&cells);
+        if !goals.contains(&cell) {
+            goals.push(cell);
+        }
+    }
+
+    let target = options.boxes as u32 * (options.difficulty.max(1) * 2 + 1);
+    let best = (0..ATTEMPTS)
+        .filter_map(|_| walk_back(&mut random, &floor, &cells, &goals, target))
+        .min_by_key(|walk| (walk.left_home, (walk.score as i64 - target as i64).abs()))
+        .ok_or("Couldn't pull the boxes off their goals, try another seed")?;
+
+    // Only the walls around the floor are kept, anything further out is left empty
+    let mut grid = Grid::default();
+    for y in 0..options.height {
+        for x in 0..options.width {
+            let touches_floor = (x.saturating_sub(1)..=x + 1)
+                .any(|nx| (y.saturating_sub(1)..=y + 1).any(|ny| floor.contains(&(nx, ny))));
+            let cell = grid.cell_mut(x as usize, y as usize);
+            cell.floor = touches_floor;
+            cell.wall = touches_floor && !floor.contains(&(x, y));
+        }
+    }
+    for (x, y) in goals {
+        grid.cell_mut(x as usize, y as usize).box_spot = Some(BoxColour::Plain);
+    }
+    for (x, y) in best.boxes {
+        grid.cell_mut(x as usize, y as usize).the_box = Some(BoxColour::Plain);
+    }
+    grid.cell_mut(best.player.0 as usize, best.player.1 as usize)
+        .player = true;
+
+    grid.meta.title = format!("Generated {}", options.seed);
+    grid.meta.difficulty = Some(options.difficulty);
+    grid.meta
+        .extra
+        .insert("Seed".to_string(), options.seed.to_string());
+    Ok(grid)
+}
+
+// A random walk from the middle clears floor until about half the inside of the room is open,
+// sometimes taking a neighbour along so there is room to get around the boxes
+fn carve_room(random: &mut Random, options: &GeneratorOptions) -> HashSet<Cell> {
+    let inside = |(x, y): Cell| x > 0 && y > 0 && x < options.width - 1 && y < options.height - 1;
+    let area = (options.width as usize - 2) * (options.height as usize - 2);
+
+    let mut floor = HashSet::new();
+    let mut cell = (options.width / 2, options.height / 2);
+    floor.insert(cell);
+    while floor.len() < area * 11 / 20 {
+        let direction = random.pick(&Direction::SQUARE);
+        let next = match Topology::Square
+            .step(cell, direction)
+            .filter(|next| inside(*next))
+        {
+            Some(next) => next,
+            None => continue,
+        };
+
+        cell = next;
+        floor.insert(cell);
+        if random.chance(40) {
+            let side = random.pick(&Direction::SQUARE);
+            if let Some(side) = Topology::Square
+                .step(cell, side)
+                .filter(|side| inside(*side))
+            {
+                floor.insert(side);
+            }
+        }
+    }
+    floor
+}
+
+// Pull boxes around until the score reaches `target`, or give up if every box is still home.
+// The score is how far each box is from the nearest goal, since any goal will do, plus one
+// for each time the player changes which box it is moving, since that is where most of the
+// thinking goes
+fn walk_back(
+    random: &mut Random,
+    floor: &HashSet<Cell>,
+    cells: &[Cell],
+    goals: &[Cell],
+    target: u32,
+) -> Option<Walk> {
+    let mut boxes = goals.to_vec();
+    let mut player = random.pick(cells);
+    if boxes.contains(&player) {
+        return None;
+    }
+
+    let mut switches = 0;
+    let mut last_pulled = None;
+    let score = |boxes: &[Cell], switches: u32| {
+        let distance = |a: &Cell, b: &Cell| {
+            (a.0 as i32 - b.0 as i32).unsigned_abs() + (a.1 as i32 - b.1 as i32).unsigned_abs()
+        };
+        let nearest = |cell: &Cell| goals.iter().map(|goal| distance(cell, goal)).min();
+        boxes.iter().filter_map(nearest).sum::<u32>() + switches
+    };
+
+    for _ in 0..target * 20 {
+        if score(&boxes, switches) >= target {
+            break;
+        }
+
+        let is_free = |cell: &Cell, boxes: &[Cell]| floor.contains(cell) && !boxes.contains(cell);
+        let mut walks = Vec::new();
+        let mut pulls = Vec::new();
+        for direction in Direction::SQUARE.iter().copied() {
+            let next = match Topology::Square.step(player, direction) {
+                Some(next) if is_free(&next, &boxes) => next,
+                _ => continue,
+            };
+            walks.push(next);
+
+            let behind = Topology::Square.step(player, direction.opposite());
+            if let Some(index) = behind.and_then(|behind| boxes.iter().position(|b| *b == behind)) {
+                pulls.push((next, index));
+            }
+        }
+
+        if !pulls.is_empty() && random.chance(60) {
+            let (next, index) = random.pick(&pulls);
+            boxes[index] = player;
+            player = next;
+            if last_pulled.is_some_and(|last| last != index) {
+                switches += 1;
+            }
+            last_pulled = Some(index);
+        } else if !walks.is_empty() {
+            player = random.pick(&walks);
+        } else {
+            break;
+        }
+    }
+
+    let left_home = boxes.iter().filter(|cell| goals.contains(cell)).count();
+    if left_home == boxes.len() {
+        return None;
+    }
+    Some(Walk {
+        score: score(&boxes, switches),
+        boxes,
+        player,
+        left_home,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::headless_world;
+    use crate::levels::Level;
+    use crate::map::write_tokens;
+    use crate::solver::{solve, Puzzle};
+
+    fn options(seed: u64) -> GeneratorOptions {
+        GeneratorOptions {
+            width: 8,
+            height: 7,
+            boxes: 2,
+            difficulty: 2,
+            seed,
+        }
+    }
+
+    #[test]
+    fn generated_levels_can_be_solved() {
+        for seed in 0..4 {
+            let level = Level {
+                id: "test:1".to_string(),
+                number: 1,
+                grid: generate(&options(seed)).expect("Expected a level"),
+            };
+            let puzzle = Puzzle::from_world(&headless_world(&level)).expect("Expected a player");
+            assert!(
+                solve(&puzzle).is_some(),
+                "seed {}\n{}",
+                seed,
+                write_tokens(&level.grid)
+            );
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_level() {
+        let tokens = |seed| write_tokens(&generate(&options(seed)).expect("Expected a level"));
+        assert_eq!(tokens(7), tokens(7));
+        assert_ne!(tokens(7), tokens(8));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::board::{Board, Terrain};
+use crate::components::*;
+use crate::formats::{parse_collection, Format};
+use crate::map::{load_map, parse_tokens, Grid};
+use crate::replay::{state_hash, Recording};
+use crate::resources::{LevelInfo, ReverseMode};
+use crate::topology::Topology;
+use specs::{join::Join, World, WorldExt};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+const BUILTIN_MAP: &str = "
+    N N W W W W W W
+    W W W . . . . W
+    W . . . BB . . W
+    W . . RB . . . W
+    W . P . . . . W
+    W . . . . RS . W
+    W . . BS . . . W
+    W . . . . . . W
+    W W W W W W W W
+    ";
+
+// A level as read from a collection, before it is loaded into a world
+#[derive(Clone)]
+pub struct Level {
+    pub id: String,
+    pub number: u32,
+    pub grid: Grid,
+}
+
+pub fn builtin_level() -> Level {
+    let mut grid = parse_tokens(BUILTIN_MAP).expect("Expected the built-in map to parse");
+    grid.meta.title = "Red and Blue".to_string();
+
+    Level {
+        id: "builtin:1".to_string(),
+        number: 1,
+        grid,
+    }
+}
+
+// The file's extension picks the format, and levels are numbered from one in file order
+pub fn read_collection(path: &Path) -> Result<Vec<Level>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Couldn't read {}: {}", path.display(), error))?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let grids = parse_collection(Format::from_path(path), &contents)
+        .map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    Ok(grids
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut grid)| {
+            let number = index as u32 + 1;
+            if grid.meta.title.is_empty() {
+                grid.meta.title = format!("Level {}", number);
+            }
+            Level {
+                id: format!("{}:{}", name, number),
+                number,
+                grid,
+            }
+        })
+        .collect())
+}
+
+// The `number`th level of a collection, counting from one
+pub fn read_level(path: &Path, number: u32) -> Result<Level, String> {
+    read_collection(path)?
+        .into_iter()
+        .find(|level| level.number == number)
+        .ok_or_else(|| format!("{} has no level {}", path.display(), number))
+}
+
+pub fn initialize_level(world: &mut World, level: &Level) {
+    load_map(world, &level.grid);
+    world.insert(LevelInfo {
+        id: level.id.clone(),
+        number: level.number,
+        meta: level.grid.meta.clone(),
+    });
+    world.insert(level.grid.meta.topology);
+
+    let initial_hash = state_hash(world);
+    world.insert(Recording::new(&level.id, initial_hash));
+}
+
+// Switch a freshly loaded level to reverse play: every box goes onto a goal it fits, and the
+// cells the boxes started on become what has to be filled to win
+pub fn reverse_level(world: &mut World) -> Result<(), String> {
+    let targets = {
+        let entities = world.entities();
+        let mut positions = world.write_storage::<Position>();
+        let boxes = world.read_storage::<Box>();
+        let box_spots = world.read_storage::<BoxSpot>();
+        let players = world.read_storage::<Player>();
+
+        let targets = (&positions, &boxes)
+            .join()
+            .map(|t| ((t.0.x, t.0.y), t.1.colour))
+            .collect::<Vec<_>>();
+
+        // Coloured goals take a box of their own colour before falling back on a plain one,
+        // and plain goals, which any box fits, go last
+        let mut goals = (&positions, &box_spots)
+            .join()
+            .map(|t| (*t.0, t.1.colour))
+            .collect::<Vec<_>>();
+        goals.sort_by_key(|(_position, colour)| *colour == BoxColour::Plain);
+        if goals.len() != targets.len() {
+            return Err("Reverse play needs as many boxes as goals".to_string());
+        }
+
+        let mut unplaced = (&entities, &boxes)
+            .join()
+            .map(|t| (t.0, t.1.colour))
+            .collect::<Vec<_>>();
+        for (goal, colour) in goals {
+            let index = unplaced
+                .iter()
+                .position(|(_entity, box_colour)| *box_colour == colour)
+                .or_else(|| unplaced.iter().position(|t| t.1.fits(colour)))
+                .ok_or("Reverse play needs a box that fits every goal")?;
+            let (entity, _colour) = unplaced.remove(index);
+            let _ = positions.insert(entity, goal);
+        }
+
+        // A player that started on a goal steps off it onto the nearest free cell
+        let movables = world.read_storage::<Movable>();
+        let immovables = world.read_storage::<Immovable>();
+        let topology = *world.read_resource::<Topology>();
+        let board = Board::new(&entities, &movables, &immovables, &positions)
+            .with_terrain(Terrain::default().with_topology(topology));
+        let box_cells = (&positions, &boxes)
+            .join()
+            .map(|t| (t.0.x, t.0.y))
+            .collect::<HashSet<_>>();
+        let displaced = (&entities, &positions, &players)
+            .join()
+            .filter(|t| box_cells.contains(&(t.1.x, t.1.y)))
+            .map(|t| (t.0, (t.1.x, t.1.y)))
+            .collect::<Vec<_>>();
+        for (entity, start) in displaced {
+            let free = nearest_free_cell(&board, start)
+                .ok_or("Reverse play has nowhere to put the player")?;
+            if let Some(position) = positions.get_mut(entity) {
+                position.x = free.0;
+                position.y = free.1;
+            }
+        }
+
+        targets
+    };
+    world.insert(ReverseMode {
+        enabled: true,
+        targets,
+    });
+
+    // Records and replays of reverse play are kept apart from the level's own
+    let level_id = {
+        let mut level_info = world.write_resource::<LevelInfo>();
+        level_info.id = format!("{}:reverse", level_info.id);
+        level_info.id.clone()
+    };
+    let initial_hash = state_hash(world);
+    world.insert(Recording::new(&level_id, initial_hash));
+
+    Ok(())
+}
+
+// Breadth-first through anything but immovables, stopping at the first cell nothing stands on
+fn nearest_free_cell(board: &Board, start: (u8, u8)) -> Option<(u8, u8)> {
+    let mut seen = HashSet::new();
+    let mut frontier = VecDeque::new();
+    frontier.push_back(start);
+    seen.insert(start);
+
+    while let Some(cell) = frontier.pop_front() {
+        if board.is_empty(cell) {
+            return Some(cell);
+        }
+        for direction in board.terrain.directions().iter() {
+            let next = board
+                .terrain
+                .step(cell, *direction)
+                .filter(|next| board.contains(*next) && !board.immovables.contains_key(next));
+            if let Some(next) = next {
+                if seen.insert(next) {
+                    frontier.push_back(next);
+                }
+            }
+        }
+    }
+
+    None
+}
@@ -1,32 +1,120 @@
 use ggez::{
     conf, event,
-    event::{KeyCode, KeyMods},
+    event::{Axis, Button, KeyCode, KeyMods, MouseButton},
+    graphics,
+    input::gamepad::GamepadId,
     timer, Context, GameResult,
 };
-use specs::RunNow;
+use specs::{Join, RunNow};
 use specs::{World, WorldExt};
 use std::path;
+use std::process;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
 
+mod actions;
 mod audio;
+mod bindings;
+mod board;
+mod cli;
 mod components;
 mod constants;
 mod entities;
 mod events;
+mod formats;
+mod gamepad;
+mod generator;
+mod levels;
 mod map;
+mod pathfinding;
+mod records;
+mod replay;
 mod resources;
+mod solver;
 mod systems;
+mod topology;
 
+use actions::*;
+use bindings::*;
 use components::*;
-use map::*;
+use constants::{MAX_QUEUED_ACTIONS, SOLUTION_STEP_INTERVAL, TILE_WIDTH, WALK_STEP_INTERVAL};
+use gamepad::*;
+use levels::*;
+use pathfinding::*;
+use records::*;
+use replay::*;
 use resources::*;
+use solver::*;
 use systems::*;
 
 struct Game {
     world: World,
+    solving: Option<PendingSolve>,
+}
+
+// The solver runs on a worker thread so the window keeps responding, then its answer is used as
+// a hint or played out to watch
+enum SolvePurpose {
+    Hint,
+    Watch,
+}
+
+struct PendingSolve {
+    purpose: SolvePurpose,
+    // An answer for a board that has changed since it was asked for is dropped
+    state_hash: u64,
+    receiver: Receiver<Option<Vec<Direction>>>,
 }
 
 impl event::EventHandler for Game {
     fn update(&mut self, context: &mut Context) -> GameResult {
+        let queue_is_empty = self.world.read_resource::<InputQueue>().actions.is_empty();
+        let tweening = self.world.read_resource::<MoveTween>().is_running();
+        let repeated = self.world.write_resource::<HeldMove>().tick(
+            timer::delta(context),
+            queue_is_empty,
+            tweening,
+        );
+        if let Some(direction) = repeated {
+            self.queue_action(Action::Move(direction));
+        }
+
+        self.poll_solve();
+
+        let playback_finished = {
+            // Replayed actions skip the queue cap, they were already paced when recorded
+            let mut playback = self.world.write_resource::<Playback>();
+            let mut input_queue = self.world.write_resource::<InputQueue>();
+            input_queue
+                .actions
+                .extend(playback.tick(timer::delta(context)));
+
+            playback.active && playback.is_finished() && input_queue.actions.is_empty()
+        };
+        // Hand control back once the last replayed action has been applied
+        if playback_finished {
+            self.end_playback();
+        }
+
+        {
+            // Feed the next step of a click-to-move walk once the last one has been applied
+            let paused = self.world.read_resource::<Gameplay>().paused;
+            let mut walk_path = self.world.write_resource::<WalkPath>();
+            let mut input_queue = self.world.write_resource::<InputQueue>();
+
+            walk_path.since_step += timer::delta(context);
+            if !paused
+                && input_queue.actions.is_empty()
+                && walk_path.since_step >= WALK_STEP_INTERVAL
+            {
+                if let Some(direction) = walk_path.steps.pop_front() {
+                    input_queue.actions.push_back(Action::Move(direction));
+                    walk_path.since_step = Duration::default();
+                }
+            }
+        }
+
         {
             let mut is = InputSystem {};
             is.run_now(&self.world);
@@ -37,6 +125,11 @@ impl event::EventHandler for Game {
             es.run_now(&self.world);
         }
 
+        {
+            let mut ts = TriggerSystem {};
+            ts.run_now(&self.world);
+        }
+
         {
             let mut gs = GameStateSystem {};
             gs.run_now(&self.world);
@@ -44,7 +137,37 @@ impl event::EventHandler for Game {
 
         {
             let mut time = self.world.write_resource::<Time>();
-            time.delta += timer::delta(context)
+            time.since_start += timer::delta(context)
+        }
+
+        self.world
+            .write_resource::<MoveTween>()
+            .tick(timer::delta(context));
+
+        {
+            let gameplay = self.world.read_resource::<Gameplay>();
+            let mut level_timer = self.world.write_resource::<LevelTimer>();
+            if level_timer.is_running(&gameplay) {
+                level_timer.elapsed += timer::delta(context);
+            }
+        }
+
+        {
+            let mut records = self.world.write_resource::<Records>();
+            if records.dirty {
+                if let Err(error) = save_records(context, &mut records) {
+                    eprintln!("Couldn't save records: {}", error);
+                }
+            }
+        }
+
+        {
+            let mut recording = self.world.write_resource::<Recording>();
+            for replay in recording.finished.drain(..) {
+                if let Err(error) = save_replay(context, &replay) {
+                    eprintln!("Couldn't save replay: {}", error);
+                }
+            }
         }
 
         Ok(())
@@ -56,42 +179,516 @@ impl event::EventHandler for Game {
             rs.run_now(&self.world);
         }
 
-        Ok(())
+        {
+            let mut hs = HudSystem { context };
+            hs.run_now(&self.world);
+        }
+
+        graphics::present(context)
     }
 
     fn key_down_event(
         &mut self,
-        _context: &mut Context,
+        context: &mut Context,
         keycode: KeyCode,
         _keymods: KeyMods,
-        _repeat: bool,
+        repeat: bool,
+    ) {
+        // Held keys are repeated by `HeldMove` at our own rate, not the OS's
+        if repeat {
+            return;
+        }
+
+        if keycode == KeyCode::F1 {
+            let open = self.world.read_resource::<RebindScreen>().open;
+            let rebind_screen = if open {
+                RebindScreen::default()
+            } else {
+                let topology = *self.world.read_resource::<topology::Topology>();
+                let players = self.world.read_storage::<Player>().join().count();
+                RebindScreen::open(topology, players)
+            };
+            *self.world.write_resource::<RebindScreen>() = rebind_screen;
+            return;
+        }
+
+        if self.world.read_resource::<RebindScreen>().open {
+            self.rebind_key(context, keycode);
+            return;
+        }
+
+        if keycode == KeyCode::F5 {
+            self.toggle_replay(context);
+            return;
+        }
+
+        if keycode == KeyCode::F6 {
+            self.toggle_solution(context);
+            return;
+        }
+
+        if self.world.read_resource::<Playback>().active && self.control_playback(keycode) {
+            return;
+        }
+
+        if keycode == KeyCode::F2 {
+            let mut overlay = self.world.write_resource::<Overlay>();
+            overlay.enabled = !overlay.enabled;
+            return;
+        }
+
+        if keycode == KeyCode::F3 {
+            let mut hud_options = self.world.write_resource::<HudOptions>();
+            hud_options.show_fps = !hud_options.show_fps;
+            return;
+        }
+
+        // Keys without a binding are dropped here
+        let action = self
+            .world
+            .read_resource::<KeyBindings>()
+            .action_for(keycode);
+        if let Some(action) = action {
+            if let Action::Move(direction) = action {
+                self.world
+                    .write_resource::<HeldMove>()
+                    .hold(Some(direction));
+            }
+            self.queue_action(action);
+        }
+    }
+
+    fn mouse_button_down_event(
+        &mut self,
+        _context: &mut Context,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    ) {
+        let cell = tile_at(&self.world, x, y);
+
+        // Right clicking a box selects it for the overlay, or clears the selection anywhere else
+        if button == MouseButton::Right {
+            let selected = box_at(&self.world, cell);
+            self.world.write_resource::<Overlay>().selected_box = selected;
+            return;
+        }
+
+        if button != MouseButton::Left || self.world.read_resource::<Gameplay>().paused {
+            return;
+        }
+
+        // Pressing on a box starts dragging it, anywhere else walks there; boxes can't be dragged in reverse play
+        let reverse = self.world.read_resource::<ReverseMode>().enabled;
+        if box_at(&self.world, cell).is_some() && !reverse {
+            *self.world.write_resource::<BoxDrag>() = BoxDrag {
+                from: Some(cell),
+                target: cell,
+                reachable: true,
+            };
+        } else if let Some(steps) = plan_walk(&self.world, cell) {
+            self.start_walk(steps);
+        }
+    }
+
+    fn mouse_motion_event(&mut self, _context: &mut Context, x: f32, y: f32, _dx: f32, _dy: f32) {
+        let from = match self.world.read_resource::<BoxDrag>().from {
+            Some(from) => from,
+            None => return,
+        };
+
+        let target = tile_at(&self.world, x, y);
+        if target != self.world.read_resource::<BoxDrag>().target {
+            let reachable = plan_push(&self.world, from, target).is_some();
+            let mut box_drag = self.world.write_resource::<BoxDrag>();
+            box_drag.target = target;
+            box_drag.reachable = reachable;
+        }
+    }
+
+    fn mouse_button_up_event(
+        &mut self,
+        _context: &mut Context,
+        button: MouseButton,
+        x: f32,
+        y: f32,
+    ) {
+        if button != MouseButton::Left {
+            return;
+        }
+
+        let from = self.world.write_resource::<BoxDrag>().from.take();
+        if let Some(from) = from {
+            // Dropping onto a cell the box can't reach does nothing
+            if let Some(steps) = plan_push(&self.world, from, tile_at(&self.world, x, y)) {
+                self.start_walk(steps);
+            }
+        }
+    }
+
+    fn key_up_event(&mut self, _context: &mut Context, keycode: KeyCode, _keymods: KeyMods) {
+        let action = self
+            .world
+            .read_resource::<KeyBindings>()
+            .action_for(keycode);
+
+        // Letting go of the held key hands repeating back to the gamepad, if it is holding a direction
+        if let Some(Action::Move(direction)) = action {
+            let gamepad_direction = self.world.read_resource::<GamepadState>().held_direction();
+            let mut held_move = self.world.write_resource::<HeldMove>();
+            if held_move.direction == Some(direction) {
+                held_move.hold(gamepad_direction);
+            }
+        }
+    }
+
+    fn gamepad_button_down_event(&mut self, _context: &mut Context, btn: Button, _id: GamepadId) {
+        if let Some(direction) = dpad_direction(btn) {
+            self.update_gamepad(|gamepad| gamepad.dpad = Some(direction));
+        } else if let Some(action) = action_for_button(btn) {
+            self.queue_action(action);
+        }
+    }
+
+    fn gamepad_button_up_event(&mut self, _context: &mut Context, btn: Button, _id: GamepadId) {
+        if let Some(direction) = dpad_direction(btn) {
+            self.update_gamepad(|gamepad| {
+                if gamepad.dpad == Some(direction) {
+                    gamepad.dpad = None;
+                }
+            });
+        }
+    }
+
+    fn gamepad_axis_event(
+        &mut self,
+        _context: &mut Context,
+        axis: Axis,
+        value: f32,
+        _id: GamepadId,
     ) {
+        self.update_gamepad(|gamepad| gamepad.set_axis(axis, value));
+    }
+
+    fn quit_event(&mut self, context: &mut Context) -> bool {
+        let mut recording = self.world.write_resource::<Recording>();
+        recording.finish();
+        for replay in recording.finished.drain(..) {
+            if let Err(error) = save_replay(context, &replay) {
+                eprintln!("Couldn't save replay: {}", error);
+            }
+        }
+
+        false
+    }
+
+    fn focus_event(&mut self, _context: &mut Context, gained: bool) {
+        let mut level_timer = self.world.write_resource::<LevelTimer>();
+        level_timer.focus_lost = !gained;
+    }
+}
+
+impl Game {
+    fn queue_action(&mut self, action: Action) {
+        if action == Action::Menu {
+            let mut gameplay = self.world.write_resource::<Gameplay>();
+            gameplay.paused = !gameplay.paused;
+            return;
+        }
+
+        // Everything else is ignored while the pause menu, rebinding screen or a replay is running
+        if self.world.read_resource::<Gameplay>().paused
+            || self.world.read_resource::<RebindScreen>().open
+            || !self.take_over_playback()
+        {
+            return;
+        }
+
+        // Written hints come first, then a hint plays the first move of a solution from here
+        if action == Action::Hint {
+            let hints = self.world.read_resource::<LevelInfo>().meta.hints.len();
+            let mut gameplay = self.world.write_resource::<Gameplay>();
+            if gameplay.hints_shown < hints {
+                gameplay.hints_shown += 1;
+                return;
+            }
+            drop(gameplay);
+
+            self.start_solve(SolvePurpose::Hint);
+            return;
+        }
+
+        // Taking control by hand abandons any walk in progress
+        self.world.write_resource::<WalkPath>().steps.clear();
+
+        // Drop actions once the queue is full rather than letting it run on after the key is released
         let mut input_queue = self.world.write_resource::<InputQueue>();
-        input_queue.keys_pressed.push(keycode);
+        if input_queue.actions.len() < MAX_QUEUED_ACTIONS {
+            input_queue.actions.push_back(action);
+        }
+    }
+
+    fn start_walk(&mut self, steps: Vec<Direction>) {
+        if !self.take_over_playback() {
+            return;
+        }
+
+        let mut walk_path = self.world.write_resource::<WalkPath>();
+        walk_path.steps = steps.into_iter().collect();
+        walk_path.since_step = WALK_STEP_INTERVAL;
+    }
+
+    // Apply a change to the gamepad state, moving straight away when the held direction changes
+    fn update_gamepad<F: FnOnce(&mut GamepadState)>(&mut self, change: F) {
+        let (before, after) = {
+            let mut gamepad = self.world.write_resource::<GamepadState>();
+            let before = gamepad.held_direction();
+            change(&mut gamepad);
+            (before, gamepad.held_direction())
+        };
+
+        if before != after {
+            self.world.write_resource::<HeldMove>().hold(after);
+
+            if let Some(direction) = after {
+                self.queue_action(Action::Move(direction));
+            }
+        }
+    }
+
+    // Start replaying the last saved attempt at this level, or stop the replay that is running
+    fn toggle_replay(&mut self, context: &mut Context) {
+        if self.world.read_resource::<Playback>().active {
+            self.end_playback();
+            return;
+        }
+
+        let (level_id, initial_hash) = {
+            let recording = self.world.read_resource::<Recording>();
+            (
+                recording.current.level_id.clone(),
+                recording.current.initial_hash,
+            )
+        };
+
+        // A replay recorded against a different layout would play nonsense
+        let replay = match load_latest_replay(context, &level_id) {
+            Some(replay) if replay.initial_hash == initial_hash => replay,
+            _ => return,
+        };
+
+        let mut input_queue = self.world.write_resource::<InputQueue>();
+        input_queue.actions.clear();
+        input_queue.actions.push_back(Action::Restart);
+        self.world.write_resource::<WalkPath>().steps.clear();
+        {
+            let mut recording = self.world.write_resource::<Recording>();
+            recording.finish();
+            recording.enabled = false;
+        }
+        *self.world.write_resource::<Playback>() = Playback::start(replay.timed_actions());
+    }
+
+    // Watch a solution play out from the current state, or stop the one that is playing
+    fn toggle_solution(&mut self, context: &mut Context) {
+        if self.world.read_resource::<Playback>().active {
+            self.end_playback();
+            return;
+        }
+
+        // A stored solution only applies from the level's initial state, otherwise ask the solver
+        let level_id = self.world.read_resource::<LevelInfo>().id.clone();
+        let at_start = self.world.read_resource::<History>().undo.is_empty();
+        let stored = if at_start {
+            load_solution(context, &level_id)
+        } else {
+            None
+        };
+
+        match stored {
+            Some(steps) => self.watch_solution(steps),
+            None => self.start_solve(SolvePurpose::Watch),
+        }
+    }
+
+    // The watched moves aren't the player's own, so recording stops until the player takes over
+    fn watch_solution(&mut self, steps: Vec<Direction>) {
+        self.world.write_resource::<InputQueue>().actions.clear();
+        self.world.write_resource::<WalkPath>().steps.clear();
+
+        // The attempt so far is kept as it was, and the solution isn't recorded
+        {
+            let mut recording = self.world.write_resource::<Recording>();
+            recording.finish();
+            recording.enabled = false;
+        }
+        *self.world.write_resource::<Playback>() = Playback::watch(steps, SOLUTION_STEP_INTERVAL);
+    }
+
+    // Ask for a solution from here, unless one is already being worked on. The solver only knows how to push
+    fn start_solve(&mut self, purpose: SolvePurpose) {
+        if self.solving.is_some() || self.world.read_resource::<ReverseMode>().enabled {
+            return;
+        }
+        let puzzle = match Puzzle::from_world(&self.world) {
+            Some(puzzle) => puzzle,
+            None => return,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let steps = solve(&puzzle).and_then(|lurd| parse_lurd(&lurd).ok());
+            let _ = sender.send(steps);
+        });
+        self.solving = Some(PendingSolve {
+            purpose,
+            state_hash: state_hash(&self.world),
+            receiver,
+        });
+    }
+
+    fn poll_solve(&mut self) {
+        let steps = match self
+            .solving
+            .as_ref()
+            .map(|solving| solving.receiver.try_recv())
+        {
+            Some(Ok(steps)) => steps,
+            Some(Err(TryRecvError::Empty)) | None => return,
+            Some(Err(TryRecvError::Disconnected)) => None,
+        };
+        let solving = self.solving.take().expect("Expected a pending solve");
+
+        let steps = match steps.filter(|_| solving.state_hash == state_hash(&self.world)) {
+            Some(steps) => steps,
+            None => return,
+        };
+        match solving.purpose {
+            SolvePurpose::Hint => {
+                if let Some(direction) = steps.first() {
+                    self.queue_action(Action::Move(*direction));
+                }
+            }
+            SolvePurpose::Watch => self.watch_solution(steps),
+        }
+    }
+
+    // Returns whether input may go ahead, stopping a solution being watched so the player can carry on from there
+    fn take_over_playback(&mut self) -> bool {
+        let (active, takeover) = {
+            let playback = self.world.read_resource::<Playback>();
+            (playback.active, playback.takeover)
+        };
+        if !active {
+            return true;
+        }
+        if takeover {
+            self.end_playback();
+            return true;
+        }
+        false
+    }
+
+    // Stop playback and go back to recording, from where playback left the board. Actions
+    // handed to the queue but not yet applied are dropped, and count as never played
+    fn end_playback(&mut self) {
+        *self.world.write_resource::<Playback>() = Playback::default();
+        self.world.write_resource::<InputQueue>().actions.clear();
+        let initial_hash = state_hash(&self.world);
+        self.world
+            .write_resource::<Recording>()
+            .resume(initial_hash);
+    }
+
+    // Returns whether the key was one of the playback controls
+    fn control_playback(&mut self, keycode: KeyCode) -> bool {
+        let mut playback = self.world.write_resource::<Playback>();
+        let mut input_queue = self.world.write_resource::<InputQueue>();
+
+        match keycode {
+            KeyCode::Space => playback.paused = !playback.paused,
+            KeyCode::Period => input_queue.actions.extend(playback.step_forward()),
+            KeyCode::Comma => input_queue.actions.extend(playback.step_back()),
+            KeyCode::Equals => playback.change_speed(2.0),
+            KeyCode::Minus => playback.change_speed(0.5),
+            _ => return false,
+        }
+        true
+    }
+
+    // Add the key to the action the rebinding screen is asking for, saving once every action has been asked for
+    fn rebind_key(&mut self, context: &mut Context, keycode: KeyCode) {
+        let mut rebind_screen = self.world.write_resource::<RebindScreen>();
+        let mut bindings = self.world.write_resource::<KeyBindings>();
+
+        // A key the menu can't lose is asked for again
+        if !bindings.rebind(rebind_screen.actions[rebind_screen.action_index], keycode) {
+            return;
+        }
+        rebind_screen.action_index += 1;
+
+        if rebind_screen.action_index == rebind_screen.actions.len() {
+            rebind_screen.open = false;
+            if let Err(error) = save_bindings(context, &bindings) {
+                eprintln!("Couldn't save key bindings: {}", error);
+            }
+        }
     }
 }
 
-pub fn initialize_level(world: &mut World) {
-    const MAP: &str = "
-    N N W W W W W W
-    W W W . . . . W
-    W . . . BB . . W
-    W . . RB . . . W 
-    W . P . . . . W
-    W . . . . RS . W
-    W . . BS . . . W
-    W . . . . . . W
-    W W W W W W W W
-    ";
-
-    load_map(world, MAP.to_string());
+fn tile_at(world: &World, x: f32, y: f32) -> (u8, u8) {
+    world
+        .read_resource::<topology::Topology>()
+        .cell_at(x, y, TILE_WIDTH)
 }
 
 fn main() -> GameResult {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    // Everything but play runs headless and exits without opening a window
+    let (level, reverse) = match command {
+        cli::Command::Play {
+            file: None,
+            reverse,
+            ..
+        } => (builtin_level(), reverse),
+        cli::Command::Play {
+            file: Some(file),
+            level,
+            reverse,
+        } => {
+            let level = read_level(&file, level).unwrap_or_else(|message| {
+                eprintln!("{}", message);
+                process::exit(1);
+            });
+            (level, reverse)
+        }
+        command => {
+            if let Err(message) = cli::run(command) {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+    };
+
     let mut world = World::new();
     register_components(&mut world);
     register_resources(&mut world);
-    initialize_level(&mut world);
+    initialize_level(&mut world, &level);
+    if reverse {
+        if let Err(message) = reverse_level(&mut world) {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
 
     let context_builder = ggez::ContextBuilder::new("rust_sokoban", "sokoban")
         .window_setup(conf::WindowSetup::default().title("Rust Sokoban!"))
@@ -101,8 +698,23 @@ fn main() -> GameResult {
     let (context, event_loop) = &mut context_builder.build()?;
 
     audio::initialize_sounds(&mut world, context);
+    if let Some(music) = &level.grid.meta.music {
+        audio::play_music(&mut world, context, music);
+    }
+    world.insert(load_records(context));
+    let bindings = load_bindings(context);
+    {
+        let mut held_move = world.write_resource::<HeldMove>();
+        held_move.delay = bindings.repeat_delay;
+        held_move.interval = bindings.repeat_interval;
+    }
+    world.write_resource::<InputQueue>().actions_per_tick = bindings.actions_per_tick;
+    world.insert(bindings);
 
-    let game = &mut Game { world };
+    let game = &mut Game {
+        world,
+        solving: None,
+    };
 
     event::run(context, event_loop, game)
 }
@@ -1,53 +1,470 @@
-use crate::components::{BoxColour, Position};
+use crate::components::*;
 use crate::entities::*;
-use specs::World;
+use crate::resources::LevelInfo;
+use crate::topology::Topology;
+use serde::{Deserialize, Serialize};
+use specs::{join::Join, World, WorldExt};
+use std::collections::BTreeMap;
 
-pub fn load_map(world: &mut World, map_string: String) {
-    let rows = map_string
-        .trim()
-        .split('\n')
-        .map(|r| r.trim())
-        .collect::<Vec<&str>>();
+// Everything that can share one cell of a level
+#[derive(Clone, Default, PartialEq)]
+pub struct Cell {
+    pub floor: bool,
+    pub wall: bool,
+    pub ice: bool,
+    pub hole: bool,
+    pub teleporter: Option<Teleporter>,
+    pub one_way: Option<OneWay>,
+    pub switch: Option<u8>,
+    pub door: Option<(u8, DoorKind)>,
+    // More than one when a saved player is holding several
+    pub keys: Vec<u8>,
+    pub player: bool,
+    pub the_box: Option<BoxColour>,
+    pub box_spot: Option<BoxColour>,
+}
+
+// Everything about a level besides its layout
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LevelMeta {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub difficulty: Option<u32>,
+    #[serde(default)]
+    pub par_moves: Option<u32>,
+    // Revealed one at a time, before the solver is asked for a move
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hints: Vec<String>,
+    // A directory under /themes holding replacements for any of the images
+    #[serde(default)]
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub music: Option<String>,
+    #[serde(default, skip_serializing_if = "Topology::is_square")]
+    pub topology: Topology,
+    // Anything else a level tool wants to keep with the level
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl LevelMeta {
+    // Header lines like `Author: someone` from the text formats; anything unknown is kept in `extra`
+    pub fn set(&mut self, key: &str, value: &str) {
+        let value = value.to_string();
+        match key.to_lowercase().as_str() {
+            "title" => self.title = value,
+            "author" => self.author = Some(value),
+            "difficulty" => self.difficulty = value.parse().ok(),
+            "par" | "par_moves" => self.par_moves = value.parse().ok(),
+            "hint" => self.hints.push(value),
+            "theme" => self.theme = Some(value),
+            "music" => self.music = Some(value),
+            "topology" => self.topology = Topology::from_name(&value).unwrap_or_default(),
+            _ => {
+                self.extra.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    // The reverse of `set`, as key and value pairs
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                entries.push((key.to_string(), value));
+            }
+        };
+
+        push("Author", self.author.clone());
+        push("Difficulty", self.difficulty.map(|d| d.to_string()));
+        push("Par", self.par_moves.map(|p| p.to_string()));
+        for hint in self.hints.iter() {
+            push("Hint", Some(hint.clone()));
+        }
+        push("Theme", self.theme.clone());
+        push("Music", self.music.clone());
+        if !self.topology.is_square() {
+            push("Topology", Some(self.topology.name().to_string()));
+        }
+        for (key, value) in self.extra.iter() {
+            push(key, Some(value.clone()));
+        }
+
+        entries
+    }
+}
+
+// Positions are stored as u8, and the cell past the last one has to fit too
+pub const MAX_GRID_SIZE: usize = 255;
+
+// A level laid out cell by cell, the common ground every file format converts through
+#[derive(Clone, Default)]
+pub struct Grid {
+    pub meta: LevelMeta,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Grid {
+    pub fn width(&self) -> usize {
+        self.rows.iter().map(|row| row.len()).max().unwrap_or(0)
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn cell_mut(&mut self, x: usize, y: usize) -> &mut Cell {
+        if self.rows.len() <= y {
+            self.rows.resize(y + 1, Vec::new());
+        }
+        let row = &mut self.rows[y];
+        if row.len() <= x {
+            row.resize(x + 1, Cell::default());
+        }
+        &mut row[x]
+    }
 
-    for (y, row) in rows.iter().enumerate() {
-        let columns = row.split(' ').collect::<Vec<&str>>();
+    // `cell_mut` for coordinates read from a file, which may be past what a level can hold
+    pub fn try_cell_mut(&mut self, x: usize, y: usize) -> Result<&mut Cell, String> {
+        if x >= MAX_GRID_SIZE || y >= MAX_GRID_SIZE {
+            return Err(format!(
+                "Cell {},{} is past the edge of the biggest level",
+                x, y
+            ));
+        }
+        Ok(self.cell_mut(x, y))
+    }
+}
 
-        for (x, column) in columns.iter().enumerate() {
+pub fn load_map(world: &mut World, grid: &Grid) {
+    for (y, row) in grid.rows.iter().enumerate() {
+        for (x, cell) in row.iter().enumerate() {
             let position = Position {
                 x: x as u8,
                 y: y as u8,
                 z: 0,
             };
 
-            match *column {
-                "." => create_floor(world, position),
-                "W" => {
-                    create_floor(world, position);
-                    create_wall(world, position);
-                }
-                "P" => {
-                    create_floor(world, position);
-                    create_player(world, position);
-                }
-                "BB" => {
-                    create_floor(world, position);
-                    create_box(world, position, BoxColour::Blue);
-                }
-                "BS" => {
-                    create_floor(world, position);
-                    create_box_spot(world, position, BoxColour::Blue);
-                }
-                "RB" => {
-                    create_floor(world, position);
-                    create_box(world, position, BoxColour::Red);
-                }
-                "RS" => {
-                    create_floor(world, position);
-                    create_box_spot(world, position, BoxColour::Red);
+            if cell.floor {
+                create_floor(world, position);
+            }
+            if cell.wall {
+                create_wall(world, position);
+            }
+            if cell.ice {
+                create_ice(world, position);
+            }
+            if cell.hole {
+                create_hole(world, position);
+            }
+            if let Some(teleporter) = cell.teleporter {
+                create_teleporter(world, position, teleporter);
+            }
+            if let Some(one_way) = cell.one_way {
+                create_one_way(world, position, one_way);
+            }
+            if let Some(link) = cell.switch {
+                create_switch(world, position, link);
+            }
+            if let Some((link, kind)) = cell.door {
+                create_door(world, position, link, kind);
+            }
+            for link in cell.keys.iter().copied() {
+                create_key(world, position, link);
+            }
+            if let Some(colour) = cell.box_spot {
+                create_box_spot(world, position, colour);
+            }
+            if let Some(colour) = cell.the_box {
+                create_box(world, position, colour);
+            }
+            if cell.player {
+                create_player(world, position);
+            }
+        }
+    }
+}
+
+// The project's own format: space separated tokens, where `+` stacks several in one cell, e.g. `P+BS`
+pub fn parse_tokens(map_string: &str) -> Result<Grid, String> {
+    let mut grid = Grid::default();
+
+    for (y, row) in map_string.trim().lines().enumerate() {
+        for (x, token) in row.split_whitespace().enumerate() {
+            let cell = grid.try_cell_mut(x, y)?;
+            if token == "N" {
+                continue;
+            }
+
+            cell.floor = true;
+            for part in token.split('+') {
+                match part {
+                    "." => (),
+                    "W" => cell.wall = true,
+                    "I" => cell.ice = true,
+                    "H" => cell.hole = true,
+                    "P" => cell.player = true,
+                    "BB" => cell.the_box = Some(BoxColour::BLUE),
+                    "BS" => cell.box_spot = Some(BoxColour::BLUE),
+                    "RB" => cell.the_box = Some(BoxColour::RED),
+                    "RS" => cell.box_spot = Some(BoxColour::RED),
+                    "B" => cell.the_box = Some(BoxColour::Plain),
+                    "S" => cell.box_spot = Some(BoxColour::Plain),
+                    // One-way arrows like `>` or `^p`, or a letter and a number
+                    c => {
+                        if let Some(one_way) = OneWay::from_token(c) {
+                            cell.one_way = Some(one_way);
+                        } else if !set_numbered(cell, c) {
+                            return Err(format!("Unrecognised map item {}", c));
+                        }
+                    }
                 }
-                "N" => (),
-                c => panic!("Unrecognised map item {}", c),
             }
         }
     }
+
+    Ok(grid)
+}
+
+// `B3` and `S3` for box and spot group 3, a pair of `T1`s for linked teleporters (`T1p` if
+// they only carry the player), `X1` for a switch opening doors `D1`, and `K1` for a key
+// opening locked doors `L1`
+fn set_numbered(cell: &mut Cell, token: &str) -> bool {
+    if let Some(link) = token
+        .strip_prefix('T')
+        .and_then(|rest| rest.strip_suffix('p'))
+        .and_then(|link| link.parse::<u8>().ok())
+    {
+        cell.teleporter = Some(Teleporter { link, boxes: false });
+        return true;
+    }
+
+    let mut chars = token.chars();
+    let (prefix, number) = match (chars.next(), chars.as_str().parse::<u8>()) {
+        (Some(prefix), Ok(number)) => (prefix, number),
+        _ => return false,
+    };
+
+    match prefix {
+        'B' => cell.the_box = Some(BoxColour::from_number(number)),
+        'S' => cell.box_spot = Some(BoxColour::from_number(number)),
+        'T' => {
+            cell.teleporter = Some(Teleporter {
+                link: number,
+                boxes: true,
+            })
+        }
+        'X' => cell.switch = Some(number),
+        'D' => cell.door = Some((number, DoorKind::Switch)),
+        'L' => cell.door = Some((number, DoorKind::Key)),
+        'K' => cell.keys.push(number),
+        _ => return false,
+    }
+    true
+}
+
+// Odd rows of a hex level are indented to hint at the shift, which reading ignores
+pub fn write_tokens(grid: &Grid) -> String {
+    grid.rows
+        .iter()
+        .enumerate()
+        .map(|(y, row)| {
+            let indent = if grid.meta.topology == Topology::Hex && y % 2 == 1 {
+                " "
+            } else {
+                ""
+            };
+            let tokens = row
+                .iter()
+                .map(cell_token)
+                .collect::<Vec<String>>()
+                .join(" ");
+            format!("{}{}", indent, tokens)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn cell_token(cell: &Cell) -> String {
+    if !cell.floor {
+        return "N".to_string();
+    }
+
+    let colour_token = |colour: BoxColour, kind: char| match colour {
+        BoxColour::BLUE => format!("B{}", kind),
+        BoxColour::RED => format!("R{}", kind),
+        BoxColour::Plain => kind.to_string(),
+        BoxColour::Group(number) => format!("{}{}", kind, number),
+    };
+
+    let mut parts = Vec::new();
+    if cell.wall {
+        parts.push("W".to_string());
+    }
+    if cell.ice {
+        parts.push("I".to_string());
+    }
+    if cell.hole {
+        parts.push("H".to_string());
+    }
+    if let Some(teleporter) = cell.teleporter {
+        let suffix = if teleporter.boxes { "" } else { "p" };
+        parts.push(format!("T{}{}", teleporter.link, suffix));
+    }
+    if let Some(one_way) = cell.one_way {
+        parts.push(one_way.token());
+    }
+    if let Some(link) = cell.switch {
+        parts.push(format!("X{}", link));
+    }
+    if let Some((link, kind)) = cell.door {
+        let prefix = match kind {
+            DoorKind::Switch => 'D',
+            DoorKind::Key => 'L',
+        };
+        parts.push(format!("{}{}", prefix, link));
+    }
+    for link in cell.keys.iter() {
+        parts.push(format!("K{}", link));
+    }
+    if cell.player {
+        parts.push("P".to_string());
+    }
+    if let Some(colour) = cell.the_box {
+        parts.push(colour_token(colour, 'B'));
+    }
+    if let Some(colour) = cell.box_spot {
+        parts.push(colour_token(colour, 'S'));
+    }
+
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("+")
+    }
+}
+
+// Lift the level back out of the world as it stands now, so the current position can be saved
+pub fn grid_from_world(world: &World) -> Grid {
+    let positions = world.read_storage::<Position>();
+    let renderables = world.read_storage::<Renderable>();
+    let walls = world.read_storage::<Wall>();
+    let ices = world.read_storage::<Ice>();
+    let holes = world.read_storage::<Hole>();
+    let teleporters = world.read_storage::<Teleporter>();
+    let one_ways = world.read_storage::<OneWay>();
+    let switches = world.read_storage::<Switch>();
+    let doors = world.read_storage::<Door>();
+    let keys = world.read_storage::<Key>();
+    let players = world.read_storage::<Player>();
+    let boxes = world.read_storage::<Box>();
+    let box_spots = world.read_storage::<BoxSpot>();
+
+    let mut grid = Grid {
+        meta: world.read_resource::<LevelInfo>().meta.clone(),
+        ..Grid::default()
+    };
+
+    // Every cell inside the level has a floor tile drawn under it
+    for (position, _renderable) in (&positions, &renderables).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .floor = true;
+    }
+    for (position, _wall) in (&positions, &walls).join() {
+        grid.cell_mut(position.x as usize, position.y as usize).wall = true;
+    }
+    for (position, _ice) in (&positions, &ices).join() {
+        grid.cell_mut(position.x as usize, position.y as usize).ice = true;
+    }
+    // A hole that has been filled is off the board, and its cell is plain floor
+    for (position, _hole) in (&positions, &holes).join() {
+        grid.cell_mut(position.x as usize, position.y as usize).hole = true;
+    }
+    for (position, teleporter) in (&positions, &teleporters).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .teleporter = Some(*teleporter);
+    }
+    for (position, one_way) in (&positions, &one_ways).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .one_way = Some(*one_way);
+    }
+    for (position, switch) in (&positions, &switches).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .switch = Some(switch.link);
+    }
+    // Doors go back closed, since what opens them is saved alongside
+    for (position, door) in (&positions, &doors).join() {
+        grid.cell_mut(position.x as usize, position.y as usize).door = Some((door.link, door.kind));
+    }
+    for (position, key) in (&positions, &keys).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .keys
+            .push(key.link);
+    }
+    // A key already picked up is off the board, so it goes under the player holding it to be
+    // picked up again
+    for (key, _off_board) in (&keys, !&positions).join() {
+        if let Some(position) = key.holder.and_then(|holder| positions.get(holder)) {
+            grid.cell_mut(position.x as usize, position.y as usize)
+                .keys
+                .push(key.link);
+        }
+    }
+    for (position, _player) in (&positions, &players).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .player = true;
+    }
+    for (position, the_box) in (&positions, &boxes).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .the_box = Some(the_box.colour);
+    }
+    for (position, box_spot) in (&positions, &box_spots).join() {
+        grid.cell_mut(position.x as usize, position.y as usize)
+            .box_spot = Some(box_spot.colour);
+    }
+
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{Action, Direction};
+    use crate::cli::headless_world;
+    use crate::levels::Level;
+    use crate::resources::InputQueue;
+    use crate::systems::{InputSystem, TriggerSystem};
+    use specs::RunNow;
+
+    #[test]
+    fn saving_puts_every_held_key_under_the_player_holding_it() {
+        let level = Level {
+            id: "test:1".to_string(),
+            number: 1,
+            grid: parse_tokens(
+                "
+                W W W W W W
+                W K1 P K2 . W
+                W W W W W W
+                ",
+            )
+            .expect("Expected the level to parse"),
+        };
+        let world = headless_world(&level);
+        for direction in [Direction::Left, Direction::Right, Direction::Right] {
+            world
+                .write_resource::<InputQueue>()
+                .actions
+                .push_back(Action::Move(direction));
+            InputSystem {}.run_now(&world);
+            TriggerSystem {}.run_now(&world);
+        }
+
+        let mut keys = grid_from_world(&world).rows[1][3].keys.clone();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![1, 2]);
+    }
 }
@@ -0,0 +1,295 @@
+use crate::actions::Direction;
+use crate::board::{Board, Terrain};
+use crate::components::*;
+use crate::resources::ActivePlayer;
+use specs::{join::Join, Entity, World, WorldExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Shortest walk from `start` to `goal` through empty cells, without pushing anything
+pub fn find_path(board: &Board, start: (u8, u8), goal: (u8, u8)) -> Option<Vec<Direction>> {
+    if start == goal {
+        return Some(Vec::new());
+    }
+    if !board.is_empty(goal) {
+        return None;
+    }
+
+    // Breadth-first search, remembering how each cell was first reached
+    let mut came_from: HashMap<(u8, u8), ((u8, u8), Direction)> = HashMap::new();
+    let mut frontier = VecDeque::new();
+    frontier.push_back(start);
+
+    while let Some(cell) = frontier.pop_front() {
+        if cell == goal {
+            break;
+        }
+
+        for direction in board.terrain.directions().iter().copied() {
+            // Stepping onto ice or a teleporter carries the player on to wherever it comes to rest
+            let next = match board.terrain.step(cell, direction) {
+                Some(next)
+                    if board.is_empty(next) && board.terrain.allows(cell, direction, false) =>
+                {
+                    board
+                        .terrain
+                        .settle(next, direction, false, |c| board.is_empty(c) || c == start)
+                }
+                _ => continue,
+            };
+
+            if next != start && !came_from.contains_key(&next) {
+                came_from.insert(next, (cell, direction));
+                frontier.push_back(next);
+            }
+        }
+    }
+
+    // Walk back from the goal to rebuild the steps
+    let mut steps = Vec::new();
+    let mut cell = goal;
+    while cell != start {
+        let (previous, direction) = came_from.get(&cell)?;
+        steps.push(*direction);
+        cell = *previous;
+    }
+    steps.reverse();
+
+    Some(steps)
+}
+
+// Every cell the player can walk to without pushing anything
+pub fn reachable_cells(board: &Board, start: (u8, u8)) -> HashSet<(u8, u8)> {
+    let mut reached = HashSet::new();
+    let mut frontier = vec![start];
+    reached.insert(start);
+
+    while let Some(cell) = frontier.pop() {
+        for direction in board.terrain.directions().iter().copied() {
+            let next = board.terrain.step(cell, direction).filter(|next| {
+                board.is_empty(*next) && board.terrain.allows(cell, direction, false)
+            });
+            if let Some(next) = next {
+                let next = board
+                    .terrain
+                    .settle(next, direction, false, |c| board.is_empty(c) || c == start);
+                if reached.insert(next) {
+                    frontier.push(next);
+                }
+            }
+        }
+    }
+
+    reached
+}
+
+// Plan a walk for the player from the current state of the world
+pub fn plan_walk(world: &World, target: (u8, u8)) -> Option<Vec<Direction>> {
+    let entities = world.entities();
+    let positions = world.read_storage::<Position>();
+    let movables = world.read_storage::<Movable>();
+    let immovables = world.read_storage::<Immovable>();
+    let players = world.read_storage::<Player>();
+
+    let board = Board::new(&entities, &movables, &immovables, &positions)
+        .with_terrain(Terrain::from_world(world));
+    let player = world
+        .read_resource::<ActivePlayer>()
+        .entity(&entities, &players)?;
+    let position = positions.get(player)?;
+
+    find_path(&board, (position.x, position.y), target)
+}
+
+// The box's cell and the player's cell, the state searched when planning pushes
+type PushState = ((u8, u8), (u8, u8));
+
+// Shortest sequence of walks and pushes moving one box to `target`, leaving every other box where it is
+pub fn find_push_path(
+    board: &Board,
+    player: (u8, u8),
+    box_cell: (u8, u8),
+    target: (u8, u8),
+) -> Option<Vec<Direction>> {
+    let start = (box_cell, player);
+    let (came_from, end) = search_pushes(board, start, Some(target));
+    let mut state = end?;
+
+    let mut steps = Vec::new();
+    while state != start {
+        let (previous, direction) = came_from.get(&state)?;
+        steps.push(*direction);
+        state = *previous;
+    }
+    steps.reverse();
+
+    Some(steps)
+}
+
+// Every cell the box at `box_cell` can be pushed to without moving any other box
+pub fn push_targets(board: &Board, player: (u8, u8), box_cell: (u8, u8)) -> HashSet<(u8, u8)> {
+    let (came_from, _end) = search_pushes(board, (box_cell, player), None);
+
+    came_from
+        .keys()
+        .map(|(cell, _player)| *cell)
+        .filter(|cell| *cell != box_cell)
+        .collect()
+}
+
+// Breadth-first search over box and player positions, stopping early once the box reaches `target`
+fn search_pushes(
+    board: &Board,
+    start: PushState,
+    target: Option<(u8, u8)>,
+) -> (
+    HashMap<PushState, (PushState, Direction)>,
+    Option<PushState>,
+) {
+    let (box_start, player_start) = start;
+    let mut came_from = HashMap::new();
+    let mut frontier = VecDeque::new();
+    frontier.push_back(start);
+
+    // The box being planned for and the player are free to move, everything else stays put
+    let is_free =
+        |cell: (u8, u8)| board.is_empty(cell) || cell == box_start || cell == player_start;
+
+    while let Some(state) = frontier.pop_front() {
+        let (box_cell, player) = state;
+        if Some(box_cell) == target {
+            return (came_from, Some(state));
+        }
+
+        for direction in board.terrain.directions().iter().copied() {
+            let next_player = match board.terrain.step(player, direction) {
+                Some(cell) if is_free(cell) && board.terrain.allows(player, direction, false) => {
+                    cell
+                }
+                _ => continue,
+            };
+
+            // Walking into the box pushes it, which only works if the cell beyond is free
+            let next_box = if next_player == box_cell {
+                match board.terrain.step(box_cell, direction) {
+                    Some(cell)
+                        if is_free(cell) && board.terrain.allows(box_cell, direction, true) =>
+                    {
+                        board
+                            .terrain
+                            .settle(cell, direction, true, |c| is_free(c) && c != next_player)
+                    }
+                    _ => continue,
+                }
+            } else {
+                box_cell
+            };
+
+            // Either of them left on ice or a teleporter moves on, the box first since it is in front
+            let next_player = board.terrain.settle(next_player, direction, false, |c| {
+                is_free(c) && c != next_box
+            });
+
+            let next = (next_box, next_player);
+            if next != start && !came_from.contains_key(&next) {
+                came_from.insert(next, (state, direction));
+                frontier.push_back(next);
+            }
+        }
+    }
+
+    (came_from, None)
+}
+
+// Plan the pushes for dragging the box at `box_cell` onto `target`
+pub fn plan_push(world: &World, box_cell: (u8, u8), target: (u8, u8)) -> Option<Vec<Direction>> {
+    let entities = world.entities();
+    let positions = world.read_storage::<Position>();
+    let movables = world.read_storage::<Movable>();
+    let immovables = world.read_storage::<Immovable>();
+    let players = world.read_storage::<Player>();
+
+    let board = Board::new(&entities, &movables, &immovables, &positions)
+        .with_terrain(Terrain::from_world(world));
+    let player = world
+        .read_resource::<ActivePlayer>()
+        .entity(&entities, &players)?;
+    let position = positions.get(player)?;
+
+    find_push_path(&board, (position.x, position.y), box_cell, target)
+}
+
+pub fn box_at(world: &World, cell: (u8, u8)) -> Option<Entity> {
+    let entities = world.entities();
+    let positions = world.read_storage::<Position>();
+    let boxes = world.read_storage::<Box>();
+
+    (&entities, &positions, &boxes)
+        .join()
+        .find(|(_entity, position, _box)| (position.x, position.y) == cell)
+        .map(|(entity, _position, _box)| entity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::headless_world;
+    use crate::levels::Level;
+    use crate::map::parse_tokens;
+
+    fn board(tokens: &str) -> Board {
+        let world = headless_world(&Level {
+            id: "test:1".to_string(),
+            number: 1,
+            grid: parse_tokens(tokens).expect("Expected the level to parse"),
+        });
+        let entities = world.entities();
+        let positions = world.read_storage::<Position>();
+        let movables = world.read_storage::<Movable>();
+        let immovables = world.read_storage::<Immovable>();
+        Board::new(&entities, &movables, &immovables, &positions)
+            .with_terrain(Terrain::from_world(&world))
+    }
+
+    const CORRIDOR: &str = "
+        W W W W W W
+        W P B . . W
+        W W W W W W
+        ";
+
+    #[test]
+    fn pushes_a_box_along_a_corridor() {
+        assert_eq!(
+            find_push_path(&board(CORRIDOR), (1, 1), (2, 1), (4, 1)),
+            Some(vec![Direction::Right, Direction::Right])
+        );
+    }
+
+    #[test]
+    fn cant_push_a_box_from_a_side_the_player_cant_reach() {
+        assert_eq!(
+            find_push_path(&board(CORRIDOR), (1, 1), (2, 1), (1, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn walks_around_the_box_to_push_it_back() {
+        let board = board(
+            "
+            W W W W W W
+            W . . . . W
+            W P B . . W
+            W W W W W W
+            ",
+        );
+        let steps = find_push_path(&board, (1, 2), (2, 2), (1, 2)).expect("Expected a path");
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps.last(), Some(&Direction::Left));
+    }
+
+    #[test]
+    fn push_targets_stop_where_the_box_gets_stuck() {
+        let targets = push_targets(&board(CORRIDOR), (1, 1), (2, 1));
+        assert_eq!(targets, [(3, 1), (4, 1)].iter().copied().collect());
+    }
+}
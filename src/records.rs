@@ -0,0 +1,75 @@
+use ggez::{filesystem, Context, GameResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+const RECORDS_PATH: &str = "/records.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub moves: u32,
+    pub pushes: u32,
+    #[serde(default)]
+    pub time: Duration,
+}
+
+impl Record {
+    // Fewer moves wins, with pushes and then time as the tie-breakers
+    pub fn is_better_than(&self, other: &Record) -> bool {
+        (self.moves, self.pushes, self.time) < (other.moves, other.pushes, other.time)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Records {
+    pub levels: HashMap<String, Record>,
+    #[serde(skip)]
+    pub dirty: bool,
+}
+
+impl Records {
+    pub fn best(&self, level_id: &str) -> Option<&Record> {
+        self.levels.get(level_id)
+    }
+
+    // Store the record if it beats the current best, returning whether it did
+    pub fn submit(&mut self, level_id: &str, record: Record) -> bool {
+        let is_best = match self.levels.get(level_id) {
+            Some(best) => record.is_better_than(best),
+            None => true,
+        };
+
+        if is_best {
+            self.levels.insert(level_id.to_string(), record);
+            self.dirty = true;
+        }
+
+        is_best
+    }
+}
+
+pub fn load_records(context: &mut Context) -> Records {
+    let mut contents = String::new();
+
+    match filesystem::open(context, RECORDS_PATH) {
+        Ok(mut file) => {
+            if file.read_to_string(&mut contents).is_err() {
+                return Records::default();
+            }
+        }
+        Err(_) => return Records::default(),
+    }
+
+    toml::from_str(&contents).unwrap_or_default()
+}
+
+// A failed save is only tried again once the records change, rather than every frame
+pub fn save_records(context: &mut Context, records: &mut Records) -> GameResult {
+    records.dirty = false;
+    let contents = toml::to_string(records).expect("Expected records to serialize");
+    let mut file = filesystem::create(context, RECORDS_PATH)?;
+
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
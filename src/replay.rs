@@ -0,0 +1,436 @@
+use crate::actions::{parse_lurd, Action, Direction};
+use crate::components::*;
+use crate::topology::Topology;
+use ggez::{filesystem, Context, GameResult};
+use serde::{Deserialize, Serialize};
+use specs::{join::Join, World, WorldExt};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REPLAYS_DIR: &str = "/replays";
+const SOLUTIONS_DIR: &str = "/solutions";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub at_ms: u64,
+    pub action: String,
+}
+
+// One attempt at a level, from the moment it was loaded or restarted
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Replay {
+    pub level_id: String,
+    pub initial_hash: u64,
+    pub actions: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    // Entries naming actions this build doesn't know are skipped
+    pub fn timed_actions(&self) -> Vec<(Duration, Action)> {
+        self.actions
+            .iter()
+            .filter_map(|entry| {
+                Action::from_name(&entry.action)
+                    .map(|action| (Duration::from_millis(entry.at_ms), action))
+            })
+            .collect()
+    }
+}
+
+// The attempt being played, plus finished attempts waiting to be written out
+#[derive(Default)]
+pub struct Recording {
+    pub current: Replay,
+    pub enabled: bool,
+    pub finished: Vec<Replay>,
+}
+
+impl Recording {
+    pub fn new(level_id: &str, initial_hash: u64) -> Self {
+        Recording {
+            current: Replay {
+                level_id: level_id.to_string(),
+                initial_hash,
+                actions: Vec::new(),
+            },
+            enabled: true,
+            finished: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, at: Duration, action: Action) {
+        if self.enabled {
+            self.current.actions.push(ReplayEntry {
+                at_ms: at.as_millis() as u64,
+                action: action.name().to_string(),
+            });
+        }
+    }
+
+    // Start recording again after playback. The new attempt starts empty from the board as
+    // playback left it, so watching a replay never writes it out a second time
+    pub fn resume(&mut self, initial_hash: u64) {
+        self.finish();
+        self.current.initial_hash = initial_hash;
+        self.enabled = true;
+    }
+
+    // Close the current attempt; a new one starts from the initial state
+    pub fn finish(&mut self) {
+        if !self.current.actions.is_empty() {
+            let next = Replay {
+                actions: Vec::new(),
+                ..self.current.clone()
+            };
+            self.finished
+                .push(std::mem::replace(&mut self.current, next));
+        }
+    }
+}
+
+// Feeds a list of timed actions back through the input queue
+pub struct Playback {
+    pub actions: Vec<(Duration, Action)>,
+    pub cursor: usize,
+    pub clock: Duration,
+    pub speed: f32,
+    pub paused: bool,
+    pub active: bool,
+    // Whether a bound action stops playback and plays on from there, rather than being ignored
+    pub takeover: bool,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Playback {
+            actions: Vec::new(),
+            cursor: 0,
+            clock: Duration::default(),
+            speed: 1.0,
+            paused: false,
+            active: false,
+            takeover: false,
+        }
+    }
+}
+
+impl Playback {
+    pub fn start(actions: Vec<(Duration, Action)>) -> Self {
+        Playback {
+            actions,
+            active: true,
+            ..Playback::default()
+        }
+    }
+
+    // Walk through a solution one move per `interval`, handing over to the player whenever they press a key
+    pub fn watch(directions: Vec<Direction>, interval: Duration) -> Self {
+        let actions = directions
+            .into_iter()
+            .enumerate()
+            .map(|(index, direction)| (interval * index as u32, Action::Move(direction)))
+            .collect();
+
+        Playback {
+            takeover: true,
+            ..Playback::start(actions)
+        }
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.actions.is_empty() {
+            1.0
+        } else {
+            self.cursor as f32 / self.actions.len() as f32
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.cursor >= self.actions.len()
+    }
+
+    // Advance the clock, returning the actions now due
+    pub fn tick(&mut self, delta: Duration) -> Vec<Action> {
+        if !self.active || self.paused {
+            return Vec::new();
+        }
+
+        self.clock += delta.mul_f32(self.speed);
+
+        let mut due = Vec::new();
+        while let Some((at, action)) = self.actions.get(self.cursor) {
+            if *at > self.clock {
+                break;
+            }
+            due.push(*action);
+            self.cursor += 1;
+        }
+        due
+    }
+
+    pub fn step_forward(&mut self) -> Option<Action> {
+        let (at, action) = *self.actions.get(self.cursor)?;
+        self.cursor += 1;
+        self.clock = at;
+        Some(action)
+    }
+
+    // The action that takes back the last one played; a restart can't be stepped back over
+    pub fn step_back(&mut self) -> Option<Action> {
+        let previous = self.cursor.checked_sub(1)?;
+        let undo = match self.actions[previous].1 {
+            Action::Move(_) | Action::Redo => Action::Undo,
+            Action::Undo => Action::Redo,
+            Action::SwitchPlayer => Action::SwitchPlayerBack,
+            _ => return None,
+        };
+
+        self.cursor = previous;
+        self.clock = previous
+            .checked_sub(1)
+            .map(|index| self.actions[index].0)
+            .unwrap_or_default();
+        Some(undo)
+    }
+
+    pub fn change_speed(&mut self, factor: f32) {
+        self.speed = (self.speed * factor).clamp(0.25, 16.0);
+    }
+}
+
+// A stable hash of where everything is, so a replay can check it starts from the same level
+pub fn state_hash(world: &World) -> u64 {
+    let positions = world.read_storage::<Position>();
+    let players = world.read_storage::<Player>();
+    let boxes = world.read_storage::<Box>();
+    let box_spots = world.read_storage::<BoxSpot>();
+    let walls = world.read_storage::<Wall>();
+    let ices = world.read_storage::<Ice>();
+    let teleporters = world.read_storage::<Teleporter>();
+    let one_ways = world.read_storage::<OneWay>();
+    let holes = world.read_storage::<Hole>();
+    let switches = world.read_storage::<Switch>();
+    let doors = world.read_storage::<Door>();
+    let keys = world.read_storage::<Key>();
+
+    let mut cells = Vec::new();
+    for (position, _player) in (&positions, &players).join() {
+        cells.push((position.x, position.y, 1, 0));
+    }
+    for (position, the_box) in (&positions, &boxes).join() {
+        cells.push((position.x, position.y, 2, the_box.colour.number()));
+    }
+    for (position, box_spot) in (&positions, &box_spots).join() {
+        cells.push((position.x, position.y, 3, box_spot.colour.number()));
+    }
+    for (position, _wall) in (&positions, &walls).join() {
+        cells.push((position.x, position.y, 4, 0));
+    }
+    for (position, _ice) in (&positions, &ices).join() {
+        cells.push((position.x, position.y, 13, 0));
+    }
+    for (position, teleporter) in (&positions, &teleporters).join() {
+        let kind = if teleporter.boxes { 5 } else { 12 };
+        cells.push((position.x, position.y, kind, teleporter.link));
+    }
+    for (position, _hole) in (&positions, &holes).join() {
+        cells.push((position.x, position.y, 7, 0));
+    }
+    for (position, switch) in (&positions, &switches).join() {
+        cells.push((position.x, position.y, 8, switch.link));
+    }
+    for (position, door) in (&positions, &doors).join() {
+        let kind = if door.kind == DoorKind::Key { 10 } else { 9 };
+        cells.push((position.x, position.y, kind, door.link));
+    }
+    for (position, key) in (&positions, &keys).join() {
+        cells.push((position.x, position.y, 11, key.link));
+    }
+    for (position, one_way) in (&positions, &one_ways).join() {
+        // The hex diagonals number on after the square directions
+        let direction = Direction::SQUARE
+            .iter()
+            .chain(Direction::HEX[2..].iter())
+            .position(|direction| *direction == one_way.direction)
+            .unwrap_or(0) as u8;
+        cells.push((
+            position.x,
+            position.y,
+            6,
+            direction * 4 + one_way.traffic as u8,
+        ));
+    }
+    cells.sort_unstable();
+
+    // FNV-1a, which unlike the std hasher is the same on every build. Square levels hash as
+    // they did before there were other topologies
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let topology = *world.read_resource::<Topology>();
+    if !topology.is_square() {
+        hash ^= topology as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    for (x, y, kind, group) in cells {
+        for byte in [x, y, kind, group].iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// Level ids like "builtin:1" aren't safe to use as file names as they are
+fn file_stem(level_id: &str) -> String {
+    level_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn replay_file_name(level_id: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    format!("{}/{}-{}.toml", REPLAYS_DIR, file_stem(level_id), timestamp)
+}
+
+pub fn save_replay(context: &mut Context, replay: &Replay) -> GameResult {
+    let contents = toml::to_string(replay).expect("Expected replay to serialize");
+    let _ = filesystem::create_dir(context, REPLAYS_DIR);
+    let mut file = filesystem::create(context, replay_file_name(&replay.level_id))?;
+
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_replay(context: &mut Context, path: &Path) -> Option<Replay> {
+    let mut contents = String::new();
+    let mut file = filesystem::open(context, path).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    toml::from_str(&contents).ok()
+}
+
+// The most recently saved attempt at the level, going by the timestamp in the file name
+pub fn load_latest_replay(context: &mut Context, level_id: &str) -> Option<Replay> {
+    let mut paths = filesystem::read_dir(context, REPLAYS_DIR)
+        .ok()?
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+
+    for path in paths.iter().rev() {
+        if let Some(replay) = load_replay(context, path) {
+            if replay.level_id == level_id {
+                return Some(replay);
+            }
+        }
+    }
+    None
+}
+
+// A stored LURD solution for the level, from /solutions/<level>.lurd
+pub fn load_solution(context: &mut Context, level_id: &str) -> Option<Vec<Direction>> {
+    let path = format!("{}/{}.lurd", SOLUTIONS_DIR, file_stem(level_id));
+    let mut contents = String::new();
+    let mut file = filesystem::open(context, path).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    parse_lurd(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::headless_world;
+    use crate::levels::Level;
+    use crate::map::parse_tokens;
+
+    fn world(tokens: &str) -> World {
+        headless_world(&Level {
+            id: "test:1".to_string(),
+            number: 1,
+            grid: parse_tokens(tokens).expect("Expected the level to parse"),
+        })
+    }
+
+    #[test]
+    fn ice_changes_the_state_hash() {
+        let plain = state_hash(&world("W P . B S W"));
+        assert_eq!(plain, state_hash(&world("W P . B S W")));
+        assert_ne!(plain, state_hash(&world("W P I B S W")));
+    }
+
+    #[test]
+    fn topology_changes_the_state_hash() {
+        let square = world("W P . B S W");
+        let hex = world("W P . B S W");
+        *hex.write_resource::<Topology>() = Topology::Hex;
+        assert_ne!(state_hash(&square), state_hash(&hex));
+    }
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn tick_hands_over_the_actions_due_at_the_playback_speed() {
+        let mut playback = Playback::start(vec![
+            (millis(0), Action::Move(Direction::Right)),
+            (millis(100), Action::Move(Direction::Up)),
+            (millis(300), Action::Undo),
+        ]);
+        assert_eq!(
+            playback.tick(millis(50)),
+            vec![Action::Move(Direction::Right)]
+        );
+        assert_eq!(playback.tick(millis(50)), vec![Action::Move(Direction::Up)]);
+
+        playback.paused = true;
+        assert!(playback.tick(millis(500)).is_empty());
+
+        playback.paused = false;
+        playback.speed = 2.0;
+        assert!(playback.tick(millis(50)).is_empty());
+        assert_eq!(playback.tick(millis(50)), vec![Action::Undo]);
+        assert!(playback.is_finished());
+    }
+
+    #[test]
+    fn step_back_takes_back_the_last_action_played() {
+        let mut playback = Playback::start(vec![
+            (millis(0), Action::Restart),
+            (millis(100), Action::Move(Direction::Right)),
+            (millis(200), Action::Undo),
+            (millis(300), Action::SwitchPlayer),
+        ]);
+        playback.tick(millis(300));
+
+        assert_eq!(playback.step_back(), Some(Action::SwitchPlayerBack));
+        assert_eq!((playback.cursor, playback.clock), (3, millis(200)));
+        assert_eq!(playback.step_back(), Some(Action::Redo));
+        assert_eq!(playback.step_back(), Some(Action::Undo));
+        assert_eq!((playback.cursor, playback.clock), (1, millis(0)));
+
+        // A restart can't be stepped back over
+        assert_eq!(playback.step_back(), None);
+        assert_eq!(playback.cursor, 1);
+    }
+
+    #[test]
+    fn recording_starts_empty_after_playback() {
+        let mut recording = Recording::new("test:1", 1);
+        recording.record(millis(100), Action::Move(Direction::Right));
+        recording.finish();
+        recording.enabled = false;
+        recording.record(millis(200), Action::Move(Direction::Up));
+
+        recording.resume(2);
+        assert!(recording.enabled);
+        assert!(recording.current.actions.is_empty());
+        assert_eq!(recording.current.initial_hash, 2);
+        assert_eq!(recording.finished.len(), 1);
+    }
+}
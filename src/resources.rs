@@ -1,13 +1,177 @@
+use crate::actions::{Action, Direction};
 use crate::audio::AudioStore;
+use crate::bindings::KeyBindings;
+use crate::components::{BoxColour, Player, Position};
+use crate::constants::{MOVE_TWEEN_DURATION, REPEAT_DELAY, REPEAT_INTERVAL};
 use crate::events::Event;
-use ggez::input::keyboard::KeyCode;
-use specs::World;
+use crate::gamepad::GamepadState;
+use crate::map::LevelMeta;
+use crate::records::Records;
+use crate::replay::{Playback, Recording};
+use crate::topology::Topology;
+use specs::{join::Join, world::Index, Entities, Entity, ReadStorage, World};
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 use std::{fmt, fmt::Display};
 
-#[derive(Default)]
 pub struct InputQueue {
-    pub keys_pressed: Vec<KeyCode>,
+    pub actions: VecDeque<Action>,
+    pub actions_per_tick: usize,
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        InputQueue {
+            actions: VecDeque::new(),
+            actions_per_tick: 1,
+        }
+    }
+}
+
+// A direction held down, re-sent as a move after a delay and then at a fixed rate
+pub struct HeldMove {
+    pub direction: Option<Direction>,
+    pub held_for: Duration,
+    pub next_repeat: Duration,
+    pub delay: Duration,
+    pub interval: Duration,
+}
+
+impl Default for HeldMove {
+    fn default() -> Self {
+        HeldMove {
+            direction: None,
+            held_for: Duration::default(),
+            next_repeat: Duration::default(),
+            delay: REPEAT_DELAY,
+            interval: REPEAT_INTERVAL,
+        }
+    }
+}
+
+impl HeldMove {
+    pub fn hold(&mut self, direction: Option<Direction>) {
+        if self.direction != direction {
+            self.direction = direction;
+            self.held_for = Duration::default();
+            self.next_repeat = self.delay;
+        }
+    }
+
+    // Repeats wait for the queue to drain and the last move to finish gliding, so a slow frame
+    // can't stack several moves up
+    pub fn tick(
+        &mut self,
+        delta: Duration,
+        queue_is_empty: bool,
+        tweening: bool,
+    ) -> Option<Direction> {
+        let direction = self.direction?;
+        self.held_for += delta;
+
+        if self.held_for < self.next_repeat || !queue_is_empty || tweening {
+            return None;
+        }
+
+        // Skip any repeats that were missed rather than firing them late
+        while self.next_repeat <= self.held_for {
+            self.next_repeat += self.interval;
+        }
+
+        Some(direction)
+    }
+}
+
+// Entities that just moved glide from the cell they left to where they are now
+#[derive(Default)]
+pub struct MoveTween {
+    pub from: HashMap<Index, (u8, u8)>,
+    pub elapsed: Duration,
+}
+
+impl MoveTween {
+    // An entity moved again before it finished gliding keeps gliding from where it first set off
+    pub fn start(&mut self, id: Index, from: (u8, u8)) {
+        self.from.entry(id).or_insert(from);
+        self.elapsed = Duration::default();
+    }
+
+    pub fn tick(&mut self, delta: Duration) {
+        self.elapsed += delta;
+        if self.elapsed >= MOVE_TWEEN_DURATION {
+            self.from.clear();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.from.is_empty()
+    }
+
+    // How far along the glide is, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        (self.elapsed.as_secs_f32() / MOVE_TWEEN_DURATION.as_secs_f32()).min(1.0)
+    }
+}
+
+// Steps of a click-to-move walk, fed into the input queue one at a time
+#[derive(Default)]
+pub struct WalkPath {
+    pub steps: VecDeque<Direction>,
+    pub since_step: Duration,
+}
+
+// A box being dragged with the mouse, and whether it can be pushed to the cell under the cursor
+#[derive(Default)]
+pub struct BoxDrag {
+    pub from: Option<(u8, u8)>,
+    pub target: (u8, u8),
+    pub reachable: bool,
+}
+
+// Shading for where the player can walk and where the selected box can be pushed
+#[derive(Default)]
+pub struct Overlay {
+    pub enabled: bool,
+    pub selected_box: Option<Entity>,
+}
+
+// Reverse play starts with the boxes on the goals and moving away from a box pulls it along;
+// it is won by getting every box back to where the level starts it
+#[derive(Default)]
+pub struct ReverseMode {
+    pub enabled: bool,
+    pub targets: Vec<((u8, u8), BoxColour)>,
+}
+
+// The player that moves go to on levels with more than one, cycled through with a key
+#[derive(Default)]
+pub struct ActivePlayer {
+    pub chosen: Option<Entity>,
+}
+
+impl ActivePlayer {
+    // The chosen player, or the first one until another is chosen
+    pub fn entity(&self, entities: &Entities, players: &ReadStorage<Player>) -> Option<Entity> {
+        self.chosen
+            .filter(|chosen| players.contains(*chosen))
+            .or_else(|| (entities, players).join().next().map(|t| t.0))
+    }
+
+    // On to the next player, or back to the one before if `backwards`
+    pub fn cycle(&mut self, entities: &Entities, players: &ReadStorage<Player>, backwards: bool) {
+        let all = (entities, players).join().map(|t| t.0).collect::<Vec<_>>();
+        let current = self.entity(entities, players);
+        let step = if backwards {
+            all.len().saturating_sub(1)
+        } else {
+            1
+        };
+        let index = all
+            .iter()
+            .position(|entity| Some(*entity) == current)
+            .map_or(0, |index| (index + step) % all.len());
+        self.chosen = all.get(index).copied();
+    }
 }
 
 #[derive(Default)]
@@ -15,22 +179,42 @@ pub struct EventQueue {
     pub events: Vec<Event>,
 }
 
+// Animation clock, accumulated since launch and never paused
 #[derive(Default)]
 pub struct Time {
-    pub delta: Duration,
+    pub since_start: Duration,
 }
 
-pub enum GameplayState {
-    Playing,
-    Won,
+// Gameplay clock for the current attempt, started by the first move
+#[derive(Default)]
+pub struct LevelTimer {
+    pub elapsed: Duration,
+    pub started: bool,
+    pub stopped: bool,
+    pub focus_lost: bool,
 }
 
-impl Default for GameplayState {
-    fn default() -> Self {
-        Self::Playing
+impl LevelTimer {
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    pub fn is_running(&self, gameplay: &Gameplay) -> bool {
+        self.started && !self.stopped && !self.focus_lost && !gameplay.paused
     }
 }
 
+#[derive(Default, PartialEq)]
+pub enum GameplayState {
+    #[default]
+    Playing,
+    Won,
+}
+
 impl Display for GameplayState {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_str(match self {
@@ -45,6 +229,82 @@ impl Display for GameplayState {
 pub struct Gameplay {
     pub state: GameplayState,
     pub moves_count: u32,
+    pub pushes_count: u32,
+    pub paused: bool,
+    pub hints_shown: usize,
+}
+
+pub struct LevelInfo {
+    pub id: String,
+    pub number: u32,
+    pub meta: LevelMeta,
+}
+
+impl Default for LevelInfo {
+    fn default() -> Self {
+        LevelInfo {
+            id: "builtin:1".to_string(),
+            number: 1,
+            meta: LevelMeta {
+                title: "Untitled".to_string(),
+                ..LevelMeta::default()
+            },
+        }
+    }
+}
+
+// The state of the level before a move, enough to put it back exactly. Boxes that fall into holes,
+// the holes they fill and keys that are picked up are taken off the board by removing their position,
+// so undo puts it back
+#[derive(Clone)]
+pub struct Snapshot {
+    pub positions: Vec<(Index, Position)>,
+    // Holes and keys still on the board
+    pub fixtures: Vec<(Index, Position)>,
+    pub active_player: Option<Entity>,
+    pub moves_count: u32,
+    pub pushes_count: u32,
+}
+
+#[derive(Default)]
+pub struct History {
+    pub undo: Vec<Snapshot>,
+    pub redo: Vec<Snapshot>,
+}
+
+// While open, the next key pressed is added to the keys for `actions[action_index]`
+#[derive(Default)]
+pub struct RebindScreen {
+    pub open: bool,
+    pub actions: Vec<Action>,
+    pub action_index: usize,
+}
+
+impl RebindScreen {
+    // Only the actions that do something on this level are asked for: the moves its topology
+    // allows, and switching players when there is more than one
+    pub fn open(topology: Topology, players: usize) -> Self {
+        let actions = Action::ALL
+            .iter()
+            .copied()
+            .filter(|action| match action {
+                Action::Move(direction) => topology.directions().contains(direction),
+                Action::SwitchPlayer => players > 1,
+                _ => true,
+            })
+            .collect();
+
+        RebindScreen {
+            open: true,
+            actions,
+            action_index: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HudOptions {
+    pub show_fps: bool,
 }
 
 pub fn register_resources(world: &mut World) {
@@ -52,5 +312,97 @@ pub fn register_resources(world: &mut World) {
     world.insert(EventQueue::default());
     world.insert(Gameplay::default());
     world.insert(Time::default());
+    world.insert(LevelTimer::default());
     world.insert(AudioStore::default());
+    world.insert(LevelInfo::default());
+    world.insert(HudOptions::default());
+    world.insert(Records::default());
+    world.insert(History::default());
+    world.insert(KeyBindings::default());
+    world.insert(RebindScreen::default());
+    world.insert(GamepadState::default());
+    world.insert(HeldMove::default());
+    world.insert(MoveTween::default());
+    world.insert(WalkPath::default());
+    world.insert(BoxDrag::default());
+    world.insert(Overlay::default());
+    world.insert(ActivePlayer::default());
+    world.insert(ReverseMode::default());
+    world.insert(Topology::default());
+    world.insert(Recording::default());
+    world.insert(Playback::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(direction: Direction) -> HeldMove {
+        let mut held = HeldMove {
+            delay: Duration::from_millis(300),
+            interval: Duration::from_millis(100),
+            ..HeldMove::default()
+        };
+        held.hold(Some(direction));
+        held
+    }
+
+    #[test]
+    fn held_moves_wait_for_the_delay_then_repeat() {
+        let mut held = held(Direction::Right);
+        assert_eq!(held.tick(Duration::from_millis(299), true, false), None);
+        assert_eq!(
+            held.tick(Duration::from_millis(1), true, false),
+            Some(Direction::Right)
+        );
+        assert_eq!(held.tick(Duration::from_millis(99), true, false), None);
+        assert_eq!(
+            held.tick(Duration::from_millis(1), true, false),
+            Some(Direction::Right)
+        );
+
+        // Letting go and holding another direction starts the delay over
+        held.hold(Some(Direction::Up));
+        assert_eq!(held.tick(Duration::from_millis(100), true, false), None);
+    }
+
+    #[test]
+    fn held_moves_wait_for_the_queue_and_the_glide() {
+        let mut held = held(Direction::Left);
+        assert_eq!(held.tick(Duration::from_millis(300), false, false), None);
+        assert_eq!(held.tick(Duration::default(), true, true), None);
+        assert_eq!(
+            held.tick(Duration::default(), true, false),
+            Some(Direction::Left)
+        );
+    }
+
+    #[test]
+    fn missed_repeats_are_skipped_rather_than_sent_late() {
+        let mut held = held(Direction::Down);
+        assert_eq!(
+            held.tick(Duration::from_millis(650), true, false),
+            Some(Direction::Down)
+        );
+        assert_eq!(held.next_repeat, Duration::from_millis(700));
+        assert_eq!(held.tick(Duration::from_millis(49), true, false), None);
+        assert_eq!(
+            held.tick(Duration::from_millis(1), true, false),
+            Some(Direction::Down)
+        );
+    }
+
+    #[test]
+    fn the_rebind_screen_only_asks_for_actions_the_level_uses() {
+        let square = RebindScreen::open(Topology::Square, 1).actions;
+        assert!(square.contains(&Action::Move(Direction::Up)));
+        assert!(!square.contains(&Action::Move(Direction::UpLeft)));
+        assert!(!square.contains(&Action::SwitchPlayer));
+        assert!(square.contains(&Action::Menu));
+
+        let hex = RebindScreen::open(Topology::Hex, 2).actions;
+        assert!(!hex.contains(&Action::Move(Direction::Up)));
+        assert!(hex.contains(&Action::Move(Direction::UpLeft)));
+        assert!(hex.contains(&Action::SwitchPlayer));
+    }
 }
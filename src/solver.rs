@@ -0,0 +1,687 @@
+use crate::actions::Direction;
+use crate::board::Terrain;
+use crate::components::*;
+use crate::resources::ActivePlayer;
+use specs::{join::Join, World, WorldExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Give up rather than search forever on levels too big for a breadth-first search
+const MAX_STATES: usize = 500_000;
+
+type Cell = (u8, u8);
+
+// The parts of a level the solver cares about, lifted out of the world
+pub struct Puzzle {
+    pub walls: HashSet<Cell>,
+    // Open holes, which block the player until a box fills them
+    pub holes: HashSet<Cell>,
+    pub terrain: Terrain,
+    // Doors block like walls while closed, which follows from the boxes on their switches or the keys held
+    pub doors: Vec<(Cell, u8, DoorKind)>,
+    pub switches: Vec<(Cell, u8)>,
+    // Keys still on the board, and the links of any already held
+    pub keys: Vec<(Cell, u8)>,
+    pub held: Vec<u8>,
+    pub goals: HashMap<Cell, BoxColour>,
+    pub boxes: Vec<(Cell, BoxColour)>,
+    pub player: Cell,
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Puzzle {
+    pub fn from_world(world: &World) -> Option<Self> {
+        let positions = world.read_storage::<Position>();
+        let immovables = world.read_storage::<Immovable>();
+        let boxes = world.read_storage::<Box>();
+        let box_spots = world.read_storage::<BoxSpot>();
+        let players = world.read_storage::<Player>();
+        let holes = world.read_storage::<Hole>();
+        let doors = world.read_storage::<Door>();
+        let switches = world.read_storage::<Switch>();
+        let keys = world.read_storage::<Key>();
+        let entities = world.entities();
+
+        // Solutions only ever move the active player, so any others are as good as walls
+        let player = world
+            .read_resource::<ActivePlayer>()
+            .entity(&entities, &players)?;
+        let position = positions.get(player)?;
+        let others = (&entities, &positions, &players)
+            .join()
+            .filter(|t| t.0 != player)
+            .map(|t| (t.1.x, t.1.y));
+        let (width, height) = positions.join().fold((0, 0), |(width, height), position| {
+            (width.max(position.x + 1), height.max(position.y + 1))
+        });
+
+        Some(Puzzle {
+            walls: (&positions, &immovables, !&holes, !&doors)
+                .join()
+                .map(|t| (t.0.x, t.0.y))
+                .chain(others)
+                .collect(),
+            holes: (&positions, &holes)
+                .join()
+                .map(|t| (t.0.x, t.0.y))
+                .collect(),
+            terrain: Terrain::from_world(world),
+            doors: (&positions, &doors)
+                .join()
+                .map(|t| ((t.0.x, t.0.y), t.1.link, t.1.kind))
+                .collect(),
+            switches: (&positions, &switches)
+                .join()
+                .map(|t| ((t.0.x, t.0.y), t.1.link))
+                .collect(),
+            keys: (&positions, &keys)
+                .join()
+                .map(|t| ((t.0.x, t.0.y), t.1.link))
+                .collect(),
+            held: (&keys, !&positions).join().map(|t| t.0.link).collect(),
+            goals: (&positions, &box_spots)
+                .join()
+                .map(|t| ((t.0.x, t.0.y), t.1.colour))
+                .collect(),
+            boxes: (&positions, &boxes)
+                .join()
+                .map(|t| ((t.0.x, t.0.y), t.1.colour))
+                .collect(),
+            player: (position.x, position.y),
+            width,
+            height,
+        })
+    }
+
+    fn is_wall(&self, (x, y): Cell) -> bool {
+        x >= self.width || y >= self.height || self.walls.contains(&(x, y))
+    }
+
+    fn is_free(&self, cell: Cell, boxes: &HashSet<Cell>) -> bool {
+        !self.is_wall(cell) && !boxes.contains(&cell)
+    }
+
+    // Where the player ends up after one step, counting any slide across ice or teleport
+    fn step(&self, cell: Cell, direction: Direction, boxes: &HashSet<Cell>) -> Option<Cell> {
+        let next = self.terrain.step(cell, direction).filter(|next| {
+            self.is_free(*next, boxes) && self.terrain.allows(cell, direction, false)
+        })?;
+        Some(
+            self.terrain
+                .settle(next, direction, false, |c| self.is_free(c, boxes)),
+        )
+    }
+
+    // Where the box and then the player come to rest after pushing the box at `box_cell`, if the player
+    // can step in behind it and the box can move on; a box pushed into an open hole fills it and is gone
+    fn push(
+        &self,
+        box_cell: Cell,
+        direction: Direction,
+        occupied: &HashSet<Cell>,
+        holes: &[Cell],
+    ) -> Option<(Option<Cell>, Cell)> {
+        let mut others = occupied.clone();
+        others.remove(&box_cell);
+
+        let standing = self.terrain.step(box_cell, direction.opposite())?;
+        if !self.terrain.allows(standing, direction, false)
+            || !self.terrain.allows(box_cell, direction, true)
+        {
+            return None;
+        }
+
+        let target = self.terrain.step(box_cell, direction)?;
+        let rest = if holes.contains(&target) {
+            others.remove(&target);
+            None
+        } else if self.is_free(target, &others) {
+            let rest = self.terrain.settle(target, direction, true, |c| {
+                self.is_free(c, &others) && c != box_cell
+            });
+            others.insert(rest);
+            Some(rest)
+        } else {
+            return None;
+        };
+
+        let player = self
+            .terrain
+            .settle(box_cell, direction, false, |c| self.is_free(c, &others));
+
+        Some((rest, player))
+    }
+
+    // Everything the player can't walk through in `state`, counting closed doors
+    fn occupied(&self, state: &State) -> HashSet<Cell> {
+        let box_cells: HashSet<Cell> = state.boxes.iter().map(|(cell, _colour)| *cell).collect();
+        let is_down = |link: u8| {
+            self.switches
+                .iter()
+                .filter(|(_cell, switch)| *switch == link)
+                .all(|(cell, _switch)| box_cells.contains(cell))
+        };
+        let closed = self.doors.iter().filter(|(_cell, link, kind)| match kind {
+            DoorKind::Switch => !self.switches.iter().any(|s| s.1 == *link) || !is_down(*link),
+            DoorKind::Key => !state.held.contains(link),
+        });
+
+        box_cells
+            .iter()
+            .copied()
+            .chain(state.holes.iter().copied())
+            .chain(closed.map(|(cell, _link, _kind)| *cell))
+            .collect()
+    }
+
+    // The state after pushing the box at `box_cell` and where the player comes to rest, leaving the region for the caller
+    fn after_push(
+        &self,
+        state: &State,
+        box_cell: Cell,
+        direction: Direction,
+    ) -> Option<(State, Option<Cell>, Cell)> {
+        let (target, pushed_to) =
+            self.push(box_cell, direction, &self.occupied(state), &state.holes)?;
+
+        let mut next = state.clone();
+        let box_index = next
+            .boxes
+            .iter()
+            .position(|(cell, _colour)| *cell == box_cell)?;
+        match target {
+            Some(target) => next.boxes[box_index].0 = target,
+            None => {
+                next.boxes.remove(box_index);
+                let hole = self.terrain.step(box_cell, direction);
+                next.holes.retain(|cell| Some(*cell) != hole);
+            }
+        }
+        next.boxes.sort_unstable();
+
+        Some((next, target, pushed_to))
+    }
+
+    fn is_solved(&self, boxes: &[(Cell, BoxColour)]) -> bool {
+        self.goals.iter().all(|(cell, goal)| {
+            boxes
+                .iter()
+                .any(|(box_cell, colour)| box_cell == cell && colour.fits(*goal))
+        })
+    }
+
+    // Cells a box of each colour can still be pushed to a goal it fits from, found by pulling back from the goals.
+    // A box can also be spent on a hole or left on a switch, so those are pulled back from for every colour
+    fn live_cells(&self) -> HashMap<BoxColour, HashSet<Cell>> {
+        let mut live: HashMap<BoxColour, HashSet<Cell>> = HashMap::new();
+
+        let spent = self.pulled_back(
+            self.holes
+                .iter()
+                .copied()
+                .chain(self.switches.iter().map(|(cell, _link)| *cell)),
+        );
+        for (_cell, colour) in self.boxes.iter() {
+            live.entry(*colour)
+                .or_default()
+                .extend(spent.iter().copied());
+        }
+
+        for (goal, goal_colour) in self.goals.iter() {
+            let cells = self.pulled_back(std::iter::once(*goal));
+            for (_cell, colour) in self.boxes.iter() {
+                if colour.fits(*goal_colour) {
+                    live.entry(*colour)
+                        .or_default()
+                        .extend(cells.iter().copied());
+                }
+            }
+        }
+
+        live
+    }
+
+    // Every cell a box could be pushed from to end up on one of `targets`
+    fn pulled_back<I: Iterator<Item = Cell>>(&self, targets: I) -> HashSet<Cell> {
+        let mut cells = HashSet::new();
+        let mut frontier = Vec::new();
+        for target in targets {
+            if cells.insert(target) {
+                frontier.push(target);
+            }
+        }
+
+        while let Some(cell) = frontier.pop() {
+            // A box coming out of a teleporter was pushed onto its partner, if that pad carries boxes
+            if let Some(pad) = self
+                .terrain
+                .teleporters
+                .get(&cell)
+                .filter(|pad| !self.terrain.player_only.contains(pad))
+            {
+                if cells.insert(*pad) {
+                    frontier.push(*pad);
+                }
+            }
+
+            for direction in self.terrain.directions().iter().copied() {
+                // Pulling the box one step needs room for the player two steps away
+                let pulled = match self.terrain.step(cell, direction) {
+                    Some(pulled) if !self.is_wall(pulled) => pulled,
+                    _ => continue,
+                };
+                match self.terrain.step(pulled, direction) {
+                    Some(standing) if !self.is_wall(standing) => (),
+                    _ => continue,
+                }
+
+                if cells.insert(pulled) {
+                    frontier.push(pulled);
+                }
+            }
+        }
+
+        cells
+    }
+
+    // Flood fill of the cells the player can walk to, returning them with the smallest as the region's key.
+    // Ice, teleporters and one-way tiles make some walks one way, so there every cell is its own region
+    fn reachable(&self, start: Cell, boxes: &HashSet<Cell>) -> (HashSet<Cell>, Cell) {
+        let mut reached = HashSet::new();
+        let mut frontier = vec![start];
+        let mut key = start;
+        reached.insert(start);
+
+        while let Some(cell) = frontier.pop() {
+            key = key.min(cell);
+            for direction in self.terrain.directions().iter().copied() {
+                if let Some(next) = self.step(cell, direction, boxes) {
+                    if reached.insert(next) {
+                        frontier.push(next);
+                    }
+                }
+            }
+        }
+
+        if !self.terrain.is_plain() {
+            key = start;
+        }
+        (reached, key)
+    }
+
+    // Shortest walk between two cells around the boxes
+    fn walk(&self, from: Cell, to: Cell, boxes: &HashSet<Cell>) -> Option<Vec<Direction>> {
+        let mut came_from: HashMap<Cell, (Cell, Direction)> = HashMap::new();
+        let mut frontier = VecDeque::new();
+        frontier.push_back(from);
+
+        while let Some(cell) = frontier.pop_front() {
+            if cell == to {
+                break;
+            }
+            for direction in self.terrain.directions().iter().copied() {
+                if let Some(next) = self.step(cell, direction, boxes) {
+                    if next != from && !came_from.contains_key(&next) {
+                        came_from.insert(next, (cell, direction));
+                        frontier.push_back(next);
+                    }
+                }
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut cell = to;
+        while cell != from {
+            let (previous, direction) = came_from.get(&cell)?;
+            steps.push(*direction);
+            cell = *previous;
+        }
+        steps.reverse();
+        Some(steps)
+    }
+}
+
+// A search node: the boxes and open holes in a canonical order, the keys held, and which region the player is standing in
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct State {
+    boxes: Vec<(Cell, BoxColour)>,
+    holes: Vec<Cell>,
+    held: Vec<u8>,
+    region: Cell,
+}
+
+// What led from one state to the next
+enum Action {
+    Push(Cell, Direction),
+    // Walking over to a key and picking it up
+    PickUp(Cell),
+}
+
+// How a state was reached: the state before, and what was done there
+struct Step {
+    previous: usize,
+    action: Action,
+}
+
+// Find a solution with the fewest pushes, as a LURD string
+pub fn solve(puzzle: &Puzzle) -> Option<String> {
+    let live = puzzle.live_cells();
+    let mut start = State {
+        boxes: puzzle.boxes.clone(),
+        holes: puzzle.holes.iter().copied().collect(),
+        held: puzzle.held.clone(),
+        region: puzzle.player,
+    };
+    start.boxes.sort_unstable();
+    start.holes.sort_unstable();
+    start.held.sort_unstable();
+    start.held.dedup();
+    start.region = puzzle.reachable(puzzle.player, &puzzle.occupied(&start)).1;
+
+    // Each state remembers where the player ended up, since the region key is only a label
+    let mut states = vec![(start, puzzle.player)];
+    let mut steps: Vec<Option<Step>> = vec![None];
+    let mut seen: HashSet<State> = states.iter().map(|(state, _)| state.clone()).collect();
+    let mut frontier = VecDeque::new();
+    frontier.push_back(0);
+
+    while let Some(index) = frontier.pop_front() {
+        let (state, player) = states[index].clone();
+        if puzzle.is_solved(&state.boxes) {
+            return Some(to_lurd(puzzle, &states, &steps, index));
+        }
+        if states.len() > MAX_STATES {
+            return None;
+        }
+
+        let (reached, _region) = puzzle.reachable(player, &puzzle.occupied(&state));
+        let mut successors = Vec::new();
+
+        // Holding a key never gets in the way, so one in reach is always worth picking up
+        for (cell, link) in puzzle.keys.iter().copied() {
+            if state.held.contains(&link) || !reached.contains(&cell) {
+                continue;
+            }
+            let mut next = state.clone();
+            next.held.push(link);
+            next.held.sort_unstable();
+            successors.push((next, cell, Action::PickUp(cell)));
+        }
+
+        for (box_cell, colour) in state.boxes.iter() {
+            for direction in puzzle.terrain.directions().iter().copied() {
+                let standing = puzzle.terrain.step(*box_cell, direction.opposite());
+                if !standing.is_some_and(|standing| reached.contains(&standing)) {
+                    continue;
+                }
+                let (next, target, pushed_to) =
+                    match puzzle.after_push(&state, *box_cell, direction) {
+                        Some(pushed) => pushed,
+                        None => continue,
+                    };
+
+                let is_live =
+                    |target: Cell| live.get(colour).is_none_or(|cells| cells.contains(&target));
+                if target.is_none_or(is_live) {
+                    successors.push((next, pushed_to, Action::Push(*box_cell, direction)));
+                }
+            }
+        }
+
+        for (mut next, player, action) in successors {
+            next.region = puzzle.reachable(player, &puzzle.occupied(&next)).1;
+            if seen.insert(next.clone()) {
+                states.push((next, player));
+                steps.push(Some(Step {
+                    previous: index,
+                    action,
+                }));
+                frontier.push_back(states.len() - 1);
+            }
+        }
+    }
+
+    None
+}
+
+// Replay the chain of pushes back from the solved state, filling in the walks between them
+fn to_lurd(
+    puzzle: &Puzzle,
+    states: &[(State, Cell)],
+    steps: &[Option<Step>],
+    end: usize,
+) -> String {
+    let mut actions = Vec::new();
+    let mut index = end;
+    while let Some(step) = &steps[index] {
+        actions.push(&step.action);
+        index = step.previous;
+    }
+    actions.reverse();
+
+    let mut lurd = String::new();
+    let mut player = puzzle.player;
+    let mut state = states[0].0.clone();
+
+    for action in actions {
+        let occupied = puzzle.occupied(&state);
+        match action {
+            Action::PickUp(cell) => {
+                let walk = puzzle
+                    .walk(player, *cell, &occupied)
+                    .expect("Expected the key to be reachable");
+                lurd.extend(walk.iter().map(|direction| direction.to_lurd(false)));
+
+                let link = puzzle
+                    .keys
+                    .iter()
+                    .find(|(key_cell, _link)| key_cell == cell)
+                    .expect("Expected a key on the cell")
+                    .1;
+                state.held.push(link);
+                player = *cell;
+            }
+            Action::Push(box_cell, direction) => {
+                let standing = puzzle
+                    .terrain
+                    .step(*box_cell, direction.opposite())
+                    .expect("Expected push to start on the grid");
+                let walk = puzzle
+                    .walk(player, standing, &occupied)
+                    .expect("Expected the push position to be reachable");
+                lurd.extend(walk.iter().map(|direction| direction.to_lurd(false)));
+                lurd.push(direction.to_lurd(true));
+
+                let (next, _target, pushed_to) = puzzle
+                    .after_push(&state, *box_cell, *direction)
+                    .expect("Expected the push to be possible");
+                state = next;
+                player = pushed_to;
+            }
+        }
+    }
+
+    lurd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{headless_world, verify};
+    use crate::levels::Level;
+    use crate::map::parse_tokens;
+
+    fn level(tokens: &str) -> Level {
+        Level {
+            id: "test:1".to_string(),
+            number: 1,
+            grid: parse_tokens(tokens).expect("Expected the level to parse"),
+        }
+    }
+
+    fn puzzle(tokens: &str) -> Puzzle {
+        Puzzle::from_world(&headless_world(&level(tokens))).expect("Expected a player")
+    }
+
+    // Solve the level, then play the solution through the game's own systems
+    fn assert_solves(tokens: &str, pushes: usize) {
+        let lurd = solve(&puzzle(tokens)).expect("Expected a solution");
+        let pushed = lurd.chars().filter(|c| c.is_uppercase()).count();
+        assert_eq!(pushed, pushes, "{}", lurd);
+        assert_eq!(verify(&level(tokens), &lurd, false), Ok(()), "{}", lurd);
+    }
+
+    #[test]
+    fn push_stops_at_walls_and_boxes() {
+        let puzzle = puzzle(
+            "
+            W W W W W W
+            W P B B S W
+            W W W W W W
+            ",
+        );
+        let occupied = [(2, 1), (3, 1)].iter().copied().collect();
+        assert_eq!(
+            puzzle.push((3, 1), Direction::Right, &occupied, &[]),
+            Some((Some((4, 1)), (3, 1)))
+        );
+        assert_eq!(puzzle.push((2, 1), Direction::Right, &occupied, &[]), None);
+        assert_eq!(puzzle.push((3, 1), Direction::Up, &occupied, &[]), None);
+    }
+
+    #[test]
+    fn solves_plain_levels_in_the_fewest_pushes() {
+        assert_solves(
+            "
+            W W W W W W W
+            W . . . . . W
+            W . P B . S W
+            W . . B . . W
+            W . . . S . W
+            W W W W W W W
+            ",
+            4,
+        );
+    }
+
+    #[test]
+    fn reports_levels_without_a_solution() {
+        let puzzle = puzzle(
+            "
+            W W W W W
+            W B . S W
+            W . P . W
+            W W W W W
+            ",
+        );
+        assert_eq!(solve(&puzzle), None);
+    }
+
+    #[test]
+    fn boxes_slide_across_ice() {
+        let ice = "
+            W W W W W W W
+            W P B I I S W
+            W W W W W W W
+            ";
+        let puzzle = puzzle(ice);
+        let occupied = [(2, 1)].iter().copied().collect();
+        assert_eq!(
+            puzzle.push((2, 1), Direction::Right, &occupied, &[]),
+            Some((Some((5, 1)), (2, 1)))
+        );
+        assert_solves(ice, 1);
+    }
+
+    #[test]
+    fn one_way_tiles_only_let_boxes_through_their_way() {
+        assert_solves(
+            "
+            W W W W W W W
+            W P B >b . S W
+            W W W W W W W
+            ",
+            3,
+        );
+        let against = puzzle(
+            "
+            W W W W W W W
+            W P B <b . S W
+            W W W W W W W
+            ",
+        );
+        assert_eq!(solve(&against), None);
+    }
+
+    #[test]
+    fn teleporters_carry_boxes_unless_player_only() {
+        let carrying = "
+            W W W W W W W W W
+            W P B T1 W . S . W
+            W . . T2 W . T1 . W
+            W W W W W T2 . . W
+            W W W W W W W W W
+            ";
+        let live = puzzle(carrying).live_cells();
+        assert!(live[&BoxColour::Plain].contains(&(2, 1)));
+        assert_solves(carrying, 2);
+
+        let player_only = carrying.replace("T1", "T1p");
+        let live = puzzle(&player_only).live_cells();
+        assert!(!live[&BoxColour::Plain].contains(&(2, 1)));
+        assert_eq!(solve(&puzzle(&player_only)), None);
+    }
+
+    #[test]
+    fn boxes_fill_holes_to_open_the_way() {
+        assert_solves(
+            "
+            W W W W W W W W
+            W P B H . B S W
+            W W W W W W W W
+            ",
+            2,
+        );
+    }
+
+    #[test]
+    fn boxes_that_can_only_reach_a_hole_or_switch_are_live() {
+        let hole = "
+            W W W W W W W
+            W P B H . . W
+            W . . . . S W
+            W W W W W W W
+            ";
+        assert!(puzzle(hole).live_cells()[&BoxColour::Plain].contains(&(2, 1)));
+        assert!(
+            !puzzle(&hole.replace(" H ", " . ")).live_cells()[&BoxColour::Plain].contains(&(2, 1))
+        );
+        assert!(
+            puzzle(&hole.replace(" H ", " X1 ")).live_cells()[&BoxColour::Plain].contains(&(2, 1))
+        );
+    }
+
+    #[test]
+    fn switches_and_keys_open_doors() {
+        assert_solves(
+            "
+            W W W W W W W W W
+            W P BB . X1 W W W W
+            W . BB . . D1 . BS W
+            W . . . . W W W W
+            W W W W W W W W W
+            ",
+            7,
+        );
+        assert_solves(
+            "
+            W W W W W W W W
+            W P . K1 W W W W
+            W . BB . L1 . BS W
+            W . . . W W W W
+            W W W W W W W W
+            ",
+            4,
+        );
+    }
+}
@@ -46,7 +46,7 @@ impl<'a> System<'a> for EventSystem {
                             {
                                 // Push a new event checking if the colours match
                                 new_events.push(Event::BoxPlacedOnSpot(BoxPlacedOnSpot {
-                                    is_correct_spot: box_spot.colour == the_box.colour,
+                                    is_correct_spot: the_box.colour.fits(box_spot.colour),
                                 }))
                             }
                         }
@@ -61,6 +61,18 @@ impl<'a> System<'a> for EventSystem {
                     };
                     audio_store.play(sound);
                 }
+                Event::Teleported => {
+                    audio_store.play("teleport");
+                }
+                Event::BoxFilledHole => {
+                    audio_store.play("hole");
+                }
+                Event::KeyPickedUp => {
+                    audio_store.play("key");
+                }
+                Event::DoorOpened | Event::DoorClosed => {
+                    audio_store.play("door");
+                }
             }
         }
         event_queue.events.append(&mut new_events);
@@ -1,6 +1,8 @@
 use crate::components::*;
-use crate::resources::{Gameplay, GameplayState};
-use specs::{join::Join, ReadStorage, System, Write};
+use crate::records::{Record, Records};
+use crate::replay::{Playback, Recording};
+use crate::resources::{Gameplay, GameplayState, LevelInfo, LevelTimer, ReverseMode};
+use specs::{join::Join, Read, ReadStorage, System, Write};
 use std::collections::HashMap;
 
 pub struct GameStateSystem {}
@@ -8,13 +10,30 @@ pub struct GameStateSystem {}
 impl<'a> System<'a> for GameStateSystem {
     type SystemData = (
         Write<'a, Gameplay>,
+        Write<'a, Records>,
+        Write<'a, LevelTimer>,
+        Write<'a, Recording>,
+        Read<'a, LevelInfo>,
+        Read<'a, ReverseMode>,
+        Read<'a, Playback>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Box>,
         ReadStorage<'a, BoxSpot>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (mut gameplay_state, positions, boxes, box_spots) = data;
+        let (
+            mut gameplay_state,
+            mut records,
+            mut level_timer,
+            mut recording,
+            level_info,
+            reverse_mode,
+            playback,
+            positions,
+            boxes,
+            box_spots,
+        ) = data;
 
         // Get all boxes
         let box_positions: HashMap<(u8, u8), &Box> = (&positions, &boxes)
@@ -22,18 +41,50 @@ impl<'a> System<'a> for GameStateSystem {
             .map(|t| ((t.0.x, t.0.y), t.1))
             .collect();
 
-        // Check all box spots to see if there is a box at the same position
-        for (box_spot, position) in (&box_spots, &positions).join() {
-            if let Some(the_box) = box_positions.get(&(position.x, position.y)) {
-                if the_box.colour != box_spot.colour {
+        // Reverse play is won by getting the boxes back to where they started instead of onto the spots
+        let goals = if reverse_mode.enabled {
+            reverse_mode.targets.clone()
+        } else {
+            (&box_spots, &positions)
+                .join()
+                .map(|t| ((t.1.x, t.1.y), t.0.colour))
+                .collect()
+        };
+
+        // Check all goals to see if there is a box at the same position
+        for (cell, colour) in goals {
+            if let Some(the_box) = box_positions.get(&cell) {
+                if !the_box.colour.fits(colour) {
                     return;
                 };
             } else {
                 gameplay_state.state = GameplayState::Playing;
+                level_timer.stopped = false;
                 return;
             }
         }
 
+        // Only record the result on the frame the level is first won
+        if gameplay_state.state != GameplayState::Won {
+            level_timer.stop();
+
+            // Moves made after winning aren't part of this attempt
+            recording.finish();
+            recording.enabled = false;
+
+            // A replay or a watched solution is won for the player, so it isn't their best
+            if !playback.active {
+                records.submit(
+                    &level_info.id,
+                    Record {
+                        moves: gameplay_state.moves_count,
+                        pushes: gameplay_state.pushes_count,
+                        time: level_timer.elapsed,
+                    },
+                );
+            }
+        }
+
         gameplay_state.state = GameplayState::Won;
     }
 }
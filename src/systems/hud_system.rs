@@ -0,0 +1,226 @@
+use crate::bindings::KeyBindings;
+use crate::components::*;
+use crate::records::Records;
+use crate::replay::Playback;
+use crate::resources::{Gameplay, HudOptions, LevelInfo, LevelTimer, RebindScreen};
+use ggez::{
+    graphics,
+    graphics::{Color, DrawMode, Mesh, Rect},
+    nalgebra as na, timer, Context,
+};
+use specs::{join::Join, Read, ReadStorage, System};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+const MARGIN: f32 = 16.0;
+const LINE_HEIGHT: f32 = 20.0;
+const PROGRESS_HEIGHT: f32 = 6.0;
+
+pub enum Anchor {
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+pub struct HudSystem<'a> {
+    pub context: &'a mut Context,
+}
+
+impl<'a> System<'a> for HudSystem<'a> {
+    type SystemData = (
+        Read<'a, Gameplay>,
+        Read<'a, LevelInfo>,
+        Read<'a, LevelTimer>,
+        Read<'a, Records>,
+        Read<'a, HudOptions>,
+        Read<'a, RebindScreen>,
+        Read<'a, KeyBindings>,
+        Read<'a, Playback>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Box>,
+        ReadStorage<'a, BoxSpot>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            gameplay,
+            level_info,
+            level_timer,
+            records,
+            hud_options,
+            rebind_screen,
+            bindings,
+            playback,
+            positions,
+            boxes,
+            box_spots,
+        ) = data;
+
+        let mut lines = Vec::new();
+        lines.push(format!(
+            "Level {}: {}",
+            level_info.number, level_info.meta.title
+        ));
+        if let Some(author) = &level_info.meta.author {
+            lines.push(format!("by {}", author));
+        }
+        lines.extend(vec![
+            match level_info.meta.par_moves {
+                Some(par) => format!("Moves: {} (par {})", gameplay.moves_count, par),
+                None => format!("Moves: {}", gameplay.moves_count),
+            },
+            format!("Pushes: {}", gameplay.pushes_count),
+            format!("Time: {}", format_duration(level_timer.elapsed)),
+        ]);
+
+        // Count the boxes of each colour, and how many sit on a matching spot
+        let box_spot_colours: HashMap<(u8, u8), BoxColour> = (&box_spots, &positions)
+            .join()
+            .map(|t| ((t.1.x, t.1.y), t.0.colour))
+            .collect();
+        let mut box_counts: BTreeMap<BoxColour, (u32, u32)> = BTreeMap::new();
+
+        for (the_box, position) in (&boxes, &positions).join() {
+            let (placed, total) = box_counts.entry(the_box.colour).or_default();
+            *total += 1;
+
+            let spot = box_spot_colours.get(&(position.x, position.y));
+            if spot.is_some_and(|colour| the_box.colour.fits(*colour)) {
+                *placed += 1;
+            }
+        }
+
+        for (colour, (placed, total)) in box_counts {
+            lines.push(format!("{} boxes: {}/{}", colour, placed, total));
+        }
+
+        lines.push(match records.best(&level_info.id) {
+            Some(best) => format!(
+                "Best: {} moves, {} pushes, {}",
+                best.moves,
+                best.pushes,
+                format_duration(best.time)
+            ),
+            None => "Best: -".to_string(),
+        });
+
+        if playback.active {
+            lines.push(format!(
+                "{} {}/{} x{}{}",
+                if playback.takeover {
+                    "Solution"
+                } else {
+                    "Replay"
+                },
+                playback.cursor,
+                playback.actions.len(),
+                playback.speed,
+                if playback.paused { " (paused)" } else { "" }
+            ));
+        }
+
+        // The most recent of the level's written hints that has been asked for
+        if let Some(hint) = gameplay
+            .hints_shown
+            .checked_sub(1)
+            .and_then(|index| level_info.meta.hints.get(index))
+        {
+            lines.push(format!("Hint: {}", hint));
+        }
+
+        for (index, line) in lines.iter().enumerate() {
+            self.draw_text(line, Anchor::TopRight, index);
+        }
+
+        if playback.active {
+            self.draw_progress(playback.progress());
+        }
+
+        if rebind_screen.open {
+            let action = rebind_screen.actions[rebind_screen.action_index];
+            let prompt = format!(
+                "Press a key to add to {} (currently: {})",
+                action,
+                bindings.keys_for(action).join(", ")
+            );
+            self.draw_text(&prompt, Anchor::BottomLeft, 2);
+            self.draw_text("Rebinding keys - press F1 to cancel", Anchor::BottomLeft, 3);
+        }
+
+        if gameplay.paused {
+            self.draw_text("Paused", Anchor::BottomLeft, 1);
+        }
+        self.draw_text(&gameplay.state.to_string(), Anchor::BottomLeft, 0);
+
+        if hud_options.show_fps {
+            let fps = format!("FPS: {:.0}", timer::fps(self.context));
+            self.draw_text(&fps, Anchor::BottomRight, 0);
+        }
+    }
+}
+
+impl HudSystem<'_> {
+    // Draw a line of text relative to one of the window corners, where `line` counts away from that corner
+    pub fn draw_text(&mut self, text_string: &str, anchor: Anchor, line: usize) {
+        let text = graphics::Text::new(text_string);
+        let screen: Rect = graphics::screen_coordinates(self.context);
+        let width = text.width(self.context) as f32;
+        let offset = line as f32 * LINE_HEIGHT;
+
+        let (x, y) = match anchor {
+            Anchor::TopRight => (
+                screen.right() - MARGIN - width,
+                screen.top() + MARGIN + offset,
+            ),
+            Anchor::BottomLeft => (
+                screen.left() + MARGIN,
+                screen.bottom() - MARGIN - LINE_HEIGHT - offset,
+            ),
+            Anchor::BottomRight => (
+                screen.right() - MARGIN - width,
+                screen.bottom() - MARGIN - LINE_HEIGHT - offset,
+            ),
+        };
+
+        let destination = na::Point2::new(x, y);
+        let colour = Some(Color::new(0.0, 0.0, 0.0, 1.0));
+        let dimensions = na::Point2::new(0.0, 0.0);
+
+        graphics::queue_text(self.context, &text, dimensions, colour);
+        graphics::draw_queued_text(
+            self.context,
+            graphics::DrawParam::new().dest(destination),
+            None,
+            graphics::FilterMode::Linear,
+        )
+        .expect("Expected draw text");
+    }
+
+    // A bar along the bottom edge of the window, filled up to `fraction`
+    pub fn draw_progress(&mut self, fraction: f32) {
+        let screen: Rect = graphics::screen_coordinates(self.context);
+        let y = screen.bottom() - PROGRESS_HEIGHT;
+        let track = Rect::new(screen.left(), y, screen.w, PROGRESS_HEIGHT);
+        let filled = Rect::new(screen.left(), y, screen.w * fraction, PROGRESS_HEIGHT);
+
+        for (rect, colour) in [
+            (track, Color::new(0.0, 0.0, 0.0, 0.2)),
+            (filled, Color::new(0.2, 0.6, 0.2, 1.0)),
+        ]
+        .iter()
+        {
+            if rect.w <= 0.0 {
+                continue;
+            }
+            let mesh = Mesh::new_rectangle(self.context, DrawMode::fill(), *rect, *colour)
+                .expect("Expected progress mesh");
+            graphics::draw(self.context, &mesh, graphics::DrawParam::new())
+                .expect("Expected draw progress");
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
@@ -1,10 +1,18 @@
+use crate::actions::Action;
+use crate::board::{Board, Terrain};
 use crate::components::*;
-use crate::constants::*;
 use crate::events::{EntityMoved, Event};
-use crate::resources::{EventQueue, Gameplay, InputQueue};
-use ggez::event::KeyCode;
-use specs::{join::Join, world::Index, Entities, ReadStorage, System, Write, WriteStorage};
-use std::collections::HashMap;
+use crate::replay::{Playback, Recording};
+use crate::resources::{
+    ActivePlayer, EventQueue, Gameplay, History, InputQueue, LevelTimer, MoveTween, ReverseMode,
+    Snapshot,
+};
+use crate::systems::trigger_system::{door_states, pick_up_keys, update_doors};
+use crate::topology::Topology;
+use specs::{
+    join::Join, world::Index, Entities, Entity, Read, ReadStorage, System, Write, WriteStorage,
+};
+use std::collections::HashSet;
 
 pub struct InputSystem {}
 
@@ -13,11 +21,28 @@ impl<'a> System<'a> for InputSystem {
         Write<'a, InputQueue>,
         Write<'a, EventQueue>,
         Write<'a, Gameplay>,
+        Write<'a, LevelTimer>,
+        Write<'a, History>,
+        Write<'a, Recording>,
+        Write<'a, ActivePlayer>,
+        Write<'a, MoveTween>,
+        Read<'a, Playback>,
+        Read<'a, ReverseMode>,
+        Read<'a, Topology>,
         Entities<'a>,
         WriteStorage<'a, Position>,
         ReadStorage<'a, Player>,
         ReadStorage<'a, Movable>,
-        ReadStorage<'a, Immovable>,
+        WriteStorage<'a, Immovable>,
+        WriteStorage<'a, Renderable>,
+        ReadStorage<'a, Ice>,
+        ReadStorage<'a, Teleporter>,
+        ReadStorage<'a, OneWay>,
+        ReadStorage<'a, Hole>,
+        WriteStorage<'a, Key>,
+        ReadStorage<'a, Box>,
+        ReadStorage<'a, Switch>,
+        ReadStorage<'a, Door>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
@@ -25,85 +50,579 @@ impl<'a> System<'a> for InputSystem {
             mut input_queue,
             mut event_queue,
             mut gameplay,
+            mut level_timer,
+            mut history,
+            mut recording,
+            mut active_player,
+            mut move_tween,
+            playback,
+            reverse_mode,
+            topology,
             entities,
             mut positions,
             players,
             movables,
-            immovables,
+            mut immovables,
+            mut renderables,
+            ices,
+            teleporters,
+            one_ways,
+            holes,
+            mut keys,
+            boxes,
+            switches,
+            doors,
         ) = data;
 
-        let mut to_move = Vec::new();
-
-        for (position, _player) in (&positions, &players).join() {
-            if let Some(key) = input_queue.keys_pressed.pop() {
-                // Movables
-                let mov: HashMap<(u8, u8), Index> = (&entities, &movables, &positions)
-                    .join()
-                    .map(|t| ((t.2.x, t.2.y), t.0.id()))
-                    .collect();
-
-                // Immovables
-                let immov: HashMap<(u8, u8), Index> = (&entities, &immovables, &positions)
-                    .join()
-                    .map(|t| ((t.2.x, t.2.y), t.0.id()))
-                    .collect();
-
-                // Iterate over each element in the direction until the end of the map
-                let (start, end, is_x) = match key {
-                    KeyCode::Up => (position.y, 0, false),
-                    KeyCode::Down => (position.y, MAP_HEIGHT, false),
-                    KeyCode::Left => (position.x, 0, true),
-                    KeyCode::Right => (position.x, MAP_WIDTH, true),
-                    _ => continue,
-                };
+        let terrain =
+            Terrain::new(&ices, &teleporters, &one_ways, &positions).with_topology(*topology);
 
-                let range = if start < end {
-                    (start..=end).collect::<Vec<u8>>()
-                } else {
-                    (end..=start).rev().collect::<Vec<u8>>()
-                };
+        // Apply queued actions oldest first, so a quick sequence of keys lands exactly as typed
+        for _ in 0..input_queue.actions_per_tick {
+            let action = match input_queue.actions.pop_front() {
+                Some(action) => action,
+                None => break,
+            };
+
+            // Doors follow whatever the last action left behind before this one is applied,
+            // so a move later in the same frame finds them open or closed as they should be
+            let states = door_states(&entities, &positions, &keys, &boxes, &switches, &doors);
+            update_doors(
+                states,
+                &mut event_queue,
+                &doors,
+                &mut immovables,
+                &mut renderables,
+            );
+
+            // Moves, undos and redos are only recorded once they have done something, so stepping
+            // back over one in a replay always has something to take back
+            match action {
+                Action::Restart => {
+                    // A restart ends the attempt being recorded and begins a fresh one, unless it is replayed
+                    recording.finish();
+                    recording.enabled = !playback.active;
+                    recording.record(level_timer.elapsed, action);
+                }
+                Action::SwitchPlayer | Action::SwitchPlayerBack => {
+                    recording.record(level_timer.elapsed, action)
+                }
+                _ => (),
+            }
+
+            let direction = match action {
+                Action::Move(direction) => direction,
+                Action::Undo | Action::Redo | Action::Restart => {
+                    // Going back in time jumps straight there
+                    move_tween.from.clear();
+                    let current = take_snapshot(
+                        &gameplay,
+                        &active_player,
+                        &entities,
+                        &movables,
+                        &holes,
+                        &keys,
+                        &positions,
+                    );
+
+                    match action {
+                        Action::Undo => {
+                            if let Some(snapshot) = history.undo.pop() {
+                                recording.record(level_timer.elapsed, action);
+                                history.redo.push(current);
+                                active_player.chosen = snapshot.active_player;
+                                restore_snapshot(
+                                    snapshot,
+                                    &mut gameplay,
+                                    &entities,
+                                    &movables,
+                                    &holes,
+                                    &keys,
+                                    &mut positions,
+                                );
+                            }
+                        }
+                        Action::Redo => {
+                            if let Some(snapshot) = history.redo.pop() {
+                                recording.record(level_timer.elapsed, action);
+                                history.undo.push(current);
+                                active_player.chosen = snapshot.active_player;
+                                restore_snapshot(
+                                    snapshot,
+                                    &mut gameplay,
+                                    &entities,
+                                    &movables,
+                                    &holes,
+                                    &keys,
+                                    &mut positions,
+                                );
+                            }
+                        }
+                        _ => {
+                            // The oldest snapshot is the level as it was loaded
+                            if !history.undo.is_empty() {
+                                let initial = history.undo.remove(0);
+                                restore_snapshot(
+                                    initial,
+                                    &mut gameplay,
+                                    &entities,
+                                    &movables,
+                                    &holes,
+                                    &keys,
+                                    &mut positions,
+                                );
+                            }
+                            history.undo.clear();
+                            history.redo.clear();
+                            // Back to the first player, as when the level was loaded
+                            active_player.chosen = None;
+                            *level_timer = LevelTimer::default();
+                            gameplay.hints_shown = 0;
+                        }
+                    }
+                    continue;
+                }
+                Action::SwitchPlayer | Action::SwitchPlayerBack => {
+                    let backwards = action == Action::SwitchPlayerBack;
+                    active_player.cycle(&entities, &players, backwards);
+                    continue;
+                }
+                // Hints are turned into moves before they are queued, and the menu never reaches the queue
+                Action::Hint | Action::Menu => continue,
+            };
+
+            let mut to_move = Vec::new();
+            // The box at the front of the line and the hole it is pushed into
+            let mut filled = None;
+
+            // Only the active player moves, the others stand in the way like walls
+            let active = active_player.entity(&entities, &players);
+            for (entity, position, _player) in (&entities, &positions, &players)
+                .join()
+                .filter(|t| Some(t.0) == active)
+            {
+                let board = Board::new(&entities, &movables, &immovables, &positions)
+                    .with_terrain(terrain.clone());
+
+                // In reverse play the player only moves into a free cell, and a box right behind follows it
+                if reverse_mode.enabled {
+                    let cell = (position.x, position.y);
+                    let is_open = board.terrain.step(cell, direction).is_some_and(|next| {
+                        board.is_empty(next) && board.terrain.allows(cell, direction, false)
+                    });
+                    if !is_open {
+                        event_queue.events.push(Event::PlayerHitObstacle);
+                        continue;
+                    }
+
+                    let behind = board.terrain.step(cell, direction.opposite());
+                    let pulled = behind.and_then(|behind| {
+                        board
+                            .movables
+                            .get(&behind)
+                            .filter(|id| !players.contains(entities.entity(**id)))
+                            .filter(|_| board.terrain.allows(behind, direction, true))
+                    });
+                    // The player goes last, since it is at the front of the line
+                    if let Some(id) = pulled {
+                        to_move.push((direction, *id));
+                    }
+                    to_move.push((direction, entity.id()));
+                    continue;
+                }
 
-                for x_or_y in range {
-                    let pos = if is_x {
-                        (x_or_y, position.y)
-                    } else {
-                        (position.x, x_or_y)
-                    };
+                // Iterate over each cell in the direction until the end of the map
+                let mut next = Some((position.x, position.y));
+                while let Some(pos) = next.filter(|cell| board.contains(*cell)) {
+                    next = board.terrain.step(pos, direction);
 
                     // Try to find a movable object at the position, and if so, add it to the move list
-                    match mov.get(&pos) {
-                        Some(id) => to_move.push((key, id.clone())),
+                    match board.movables.get(&pos) {
+                        // Another player, a one-way tile it would leave or enter the wrong way, or no cell
+                        // that way at all, stops the whole line
+                        Some(id) => {
+                            let is_box = !players.contains(entities.entity(*id));
+                            let is_other_player = !is_box && !to_move.is_empty();
+                            if is_other_player
+                                || next.is_none()
+                                || !board.terrain.allows(pos, direction, is_box)
+                            {
+                                to_move.clear();
+                                event_queue.events.push(Event::PlayerHitObstacle);
+                                break;
+                            }
+                            to_move.push((direction, *id));
+                        }
                         // Try to find an immovable object at the location, and if so, clear all movements
-                        None => match immov.get(&pos) {
+                        None => match board.immovables.get(&pos) {
+                            Some(id) if holes.contains(entities.entity(*id)) => {
+                                match to_move.last() {
+                                    Some((_direction, box_id))
+                                        if !players.contains(entities.entity(*box_id)) =>
+                                    {
+                                        filled = Some((*box_id, *id));
+                                    }
+                                    _ => {
+                                        to_move.clear();
+                                        event_queue.events.push(Event::PlayerHitObstacle);
+                                    }
+                                }
+                                break;
+                            }
                             Some(_id) => {
                                 to_move.clear();
                                 event_queue.events.push(Event::PlayerHitObstacle);
+                                break;
                             }
                             None => break,
                         },
                     }
                 }
             }
+
+            if !to_move.is_empty() {
+                recording.record(level_timer.elapsed, action);
+                history.undo.push(take_snapshot(
+                    &gameplay,
+                    &active_player,
+                    &entities,
+                    &movables,
+                    &holes,
+                    &keys,
+                    &positions,
+                ));
+                history.redo.clear();
+
+                gameplay.moves_count += 1;
+                level_timer.start();
+
+                // Anything moving besides the player means a box was pushed
+                if to_move.len() > 1 {
+                    gameplay.pushes_count += 1;
+                }
+            }
+
+            let moved = to_move
+                .iter()
+                .map(|(_direction, id)| *id)
+                .collect::<Vec<_>>();
+
+            for (direction, id) in to_move {
+                let entity = entities.entity(id);
+                let cell = positions
+                    .get(entity)
+                    .map(|position| (position.x, position.y));
+                if let Some((cell, next)) =
+                    cell.and_then(|cell| Some((cell, terrain.step(cell, direction)?)))
+                {
+                    move_tween.start(id, cell);
+                    move_to(&mut positions, entity, next);
+                }
+                event_queue
+                    .events
+                    .push(Event::EntityMoved(EntityMoved { id }));
+            }
+
+            // The box drops into the hole, and both leave the board
+            if let Some((box_id, hole_id)) = filled {
+                positions.remove(entities.entity(box_id));
+                positions.remove(entities.entity(hole_id));
+                event_queue.events.push(Event::BoxFilledHole);
+            }
+
+            // Anything that ends up on ice keeps sliding until the next cell is taken, then
+            // goes through a teleporter it stops on; the front of the line first so the rest can follow it
+            for id in moved.into_iter().rev() {
+                let entity = entities.entity(id);
+                let board = Board::new(&entities, &movables, &immovables, &positions)
+                    .with_terrain(terrain.clone());
+                let cell = match positions.get(entity) {
+                    Some(position) => (position.x, position.y),
+                    None => continue,
+                };
+                let is_box = !players.contains(entity);
+                let is_free = |next| board.is_empty(next) || next == cell;
+
+                // Every cell the player passes through, for the keys on them
+                let mut passed = vec![cell];
+                let mut stop = cell;
+                for next in board.terrain.slide(cell, direction, is_box, is_free) {
+                    passed.push(next);
+                    stop = next;
+                    move_to(&mut positions, entity, next);
+                    event_queue
+                        .events
+                        .push(Event::EntityMoved(EntityMoved { id }));
+                }
+
+                if let Some(partner) = board.terrain.teleport(stop, is_box, is_free) {
+                    // Teleporting is instant, not a glide across the board
+                    move_tween.from.remove(&id);
+                    move_to(&mut positions, entity, partner);
+                    passed.push(partner);
+                    event_queue.events.push(Event::Teleported);
+                    event_queue
+                        .events
+                        .push(Event::EntityMoved(EntityMoved { id }));
+                }
+
+                if !is_box {
+                    pick_up_keys(
+                        entity,
+                        &passed,
+                        &mut event_queue,
+                        &entities,
+                        &mut keys,
+                        &mut positions,
+                    );
+                }
+            }
         }
+    }
+}
+
+fn move_to(positions: &mut WriteStorage<Position>, entity: Entity, (x, y): (u8, u8)) {
+    if let Some(position) = positions.get_mut(entity) {
+        position.x = x;
+        position.y = y;
+    }
+}
+
+fn take_snapshot(
+    gameplay: &Gameplay,
+    active_player: &ActivePlayer,
+    entities: &Entities,
+    movables: &ReadStorage<Movable>,
+    holes: &ReadStorage<Hole>,
+    keys: &WriteStorage<Key>,
+    positions: &WriteStorage<Position>,
+) -> Snapshot {
+    Snapshot {
+        positions: (entities, movables, positions)
+            .join()
+            .map(|t| (t.0.id(), *t.2))
+            .collect(),
+        fixtures: (entities, holes.mask() | keys.mask(), positions)
+            .join()
+            .map(|t| (t.0.id(), *t.2))
+            .collect(),
+        active_player: active_player.chosen,
+        moves_count: gameplay.moves_count,
+        pushes_count: gameplay.pushes_count,
+    }
+}
+
+// Anything movable, hole or key that wasn't on the board when the snapshot was taken is taken off again
+fn restore_snapshot(
+    snapshot: Snapshot,
+    gameplay: &mut Gameplay,
+    entities: &Entities,
+    movables: &ReadStorage<Movable>,
+    holes: &ReadStorage<Hole>,
+    keys: &WriteStorage<Key>,
+    positions: &mut WriteStorage<Position>,
+) {
+    let on_board = snapshot
+        .positions
+        .iter()
+        .chain(snapshot.fixtures.iter())
+        .map(|(id, _position)| *id)
+        .collect::<HashSet<Index>>();
+
+    let off_board = (entities, movables.mask() | holes.mask() | keys.mask())
+        .join()
+        .map(|t| t.0)
+        .filter(|entity| !on_board.contains(&entity.id()))
+        .collect::<Vec<_>>();
+    for entity in off_board {
+        positions.remove(entity);
+    }
+
+    for (id, position) in snapshot.positions.into_iter().chain(snapshot.fixtures) {
+        let _ = positions.insert(entities.entity(id), position);
+    }
+
+    gameplay.moves_count = snapshot.moves_count;
+    gameplay.pushes_count = snapshot.pushes_count;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::Direction;
+    use crate::cli::headless_world;
+    use crate::levels::{reverse_level, Level};
+    use crate::map::parse_tokens;
+    use crate::resources::GameplayState;
+    use crate::systems::GameStateSystem;
+    use specs::{RunNow, World, WorldExt};
+
+    fn world(tokens: &str) -> World {
+        headless_world(&Level {
+            id: "test:1".to_string(),
+            number: 1,
+            grid: parse_tokens(tokens).expect("Expected the level to parse"),
+        })
+    }
+
+    fn box_cells(world: &World) -> Vec<(u8, u8)> {
+        let positions = world.read_storage::<Position>();
+        let boxes = world.read_storage::<Box>();
+        (&positions, &boxes)
+            .join()
+            .map(|t| (t.0.x, t.0.y))
+            .collect()
+    }
 
-        if !to_move.is_empty() {
-            gameplay.moves_count += 1;
+    #[test]
+    fn keys_slid_over_open_doors_for_the_next_move_in_the_same_frame() {
+        let mut world = world(
+            "
+            W W W W W W W W W
+            W P I I+K1 I . L1 . W
+            W W W W W W W W W
+            ",
+        );
+        {
+            let mut input_queue = world.write_resource::<InputQueue>();
+            input_queue.actions_per_tick = 2;
+            input_queue
+                .actions
+                .push_back(Action::Move(Direction::Right));
+            input_queue
+                .actions
+                .push_back(Action::Move(Direction::Right));
         }
+        InputSystem {}.run_now(&world);
+        world.maintain();
 
-        for (key, id) in to_move {
-            let position = positions.get_mut(entities.entity(id));
-            if let Some(position) = position {
-                match key {
-                    KeyCode::Up => position.y -= 1,
-                    KeyCode::Down => position.y += 1,
-                    KeyCode::Left => position.x -= 1,
-                    KeyCode::Right => position.x += 1,
-                    _ => (),
-                }
+        let positions = world.read_storage::<Position>();
+        let players = world.read_storage::<Player>();
+        let player = (&positions, &players)
+            .join()
+            .next()
+            .expect("Expected a player");
+        assert_eq!((player.0.x, player.0.y), (6, 1));
+    }
+
+    #[test]
+    fn the_active_player_follows_undo_and_restart() {
+        let world = world(
+            "
+            W W W W W W
+            W P . . P W
+            W W W W W W
+            ",
+        );
+        let run = |action: Action| {
+            world
+                .write_resource::<InputQueue>()
+                .actions
+                .push_back(action);
+            InputSystem {}.run_now(&world);
+        };
+        let active = || {
+            let entities = world.entities();
+            let players = world.read_storage::<Player>();
+            let positions = world.read_storage::<Position>();
+            let entity = world
+                .read_resource::<ActivePlayer>()
+                .entity(&entities, &players)
+                .expect("Expected a player");
+            let position = positions.get(entity).expect("Expected a position");
+            (position.x, position.y)
+        };
+
+        run(Action::SwitchPlayer);
+        run(Action::Move(Direction::Left));
+        assert_eq!(active(), (3, 1));
+        run(Action::SwitchPlayerBack);
+        assert_eq!(active(), (1, 1));
+        run(Action::Undo);
+        assert_eq!(active(), (4, 1));
+        run(Action::Redo);
+        run(Action::Restart);
+        assert_eq!(active(), (1, 1));
+    }
+
+    #[test]
+    fn walls_stop_the_line_before_anything_past_them() {
+        let world = world(
+            "
+            W W W W W W
+            W P W B . W
+            W W W W W W
+            ",
+        );
+        world
+            .write_resource::<InputQueue>()
+            .actions
+            .push_back(Action::Move(Direction::Right));
+        InputSystem {}.run_now(&world);
+
+        let positions = world.read_storage::<Position>();
+        let boxes = world.read_storage::<Box>();
+        let the_box = (&positions, &boxes).join().next().expect("Expected a box");
+        assert_eq!((the_box.0.x, the_box.0.y), (3, 1));
+    }
+
+    #[test]
+    fn reverse_play_pulls_the_boxes_back_off_the_goals() {
+        let mut world = world("W S P . B . W");
+        reverse_level(&mut world).expect("Expected reverse play");
+        assert_eq!(box_cells(&world), vec![(1, 0)]);
+
+        let step_away = |world: &World| {
+            world
+                .write_resource::<InputQueue>()
+                .actions
+                .push_back(Action::Move(Direction::Right));
+            InputSystem {}.run_now(world);
+            GameStateSystem {}.run_now(world);
+        };
+
+        step_away(&world);
+        assert_eq!(box_cells(&world), vec![(2, 0)]);
+        step_away(&world);
+        assert!(world.read_resource::<Gameplay>().state == GameplayState::Playing);
+        step_away(&world);
+        assert_eq!(box_cells(&world), vec![(4, 0)]);
+        assert!(world.read_resource::<Gameplay>().state == GameplayState::Won);
+    }
+
+    #[test]
+    fn queued_actions_run_oldest_first_a_few_at_a_time() {
+        let world = world(
+            "
+            W W W W W
+            W P . . W
+            W . . . W
+            W W W W W
+            ",
+        );
+        {
+            let mut input_queue = world.write_resource::<InputQueue>();
+            input_queue.actions_per_tick = 2;
+            for direction in [Direction::Right, Direction::Down, Direction::Left] {
+                input_queue.actions.push_back(Action::Move(direction));
             }
-            event_queue
-                .events
-                .push(Event::EntityMoved(EntityMoved { id }));
         }
+        InputSystem {}.run_now(&world);
+
+        let positions = world.read_storage::<Position>();
+        let players = world.read_storage::<Player>();
+        let player = (&positions, &players)
+            .join()
+            .next()
+            .expect("Expected a player");
+        assert_eq!((player.0.x, player.0.y), (2, 2));
+        assert_eq!(
+            world
+                .read_resource::<InputQueue>()
+                .actions
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![&Action::Move(Direction::Left)]
+        );
     }
 }
@@ -1,9 +1,13 @@
 mod event_system;
 mod game_state_system;
+mod hud_system;
 mod input_system;
 mod rendering_system;
+mod trigger_system;
 
 pub use self::event_system::EventSystem;
 pub use self::game_state_system::GameStateSystem;
+pub use self::hud_system::HudSystem;
 pub use self::input_system::InputSystem;
 pub use self::rendering_system::RenderingSystem;
+pub use self::trigger_system::TriggerSystem;
@@ -1,14 +1,19 @@
+use crate::board::{Board, Terrain};
 use crate::components::*;
 use crate::constants::TILE_WIDTH;
-use crate::resources::{Gameplay, Time};
+use crate::pathfinding::{push_targets, reachable_cells};
+use crate::resources::{ActivePlayer, BoxDrag, LevelInfo, MoveTween, Overlay, ReverseMode, Time};
+use crate::topology::Topology;
 use ggez::{
-    graphics,
-    graphics::{spritebatch::SpriteBatch, Color, DrawParam, Image},
-    nalgebra as na, timer, Context,
+    filesystem, graphics,
+    graphics::{
+        spritebatch::SpriteBatch, Color, DrawMode, DrawParam, Image, Mesh, MeshBuilder, Rect,
+    },
+    nalgebra as na, Context,
 };
 use itertools::Itertools;
-use specs::{join::Join, Read, ReadStorage, System};
-use std::collections::HashMap;
+use specs::{join::Join, Entities, Read, ReadStorage, System};
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
 pub struct RenderingSystem<'a> {
@@ -17,31 +22,73 @@ pub struct RenderingSystem<'a> {
 
 impl<'a> System<'a> for RenderingSystem<'a> {
     type SystemData = (
-        Read<'a, Gameplay>,
         Read<'a, Time>,
+        Read<'a, BoxDrag>,
+        Read<'a, Overlay>,
+        Read<'a, LevelInfo>,
+        Read<'a, ActivePlayer>,
+        Read<'a, ReverseMode>,
+        Read<'a, Topology>,
+        Read<'a, MoveTween>,
+        Entities<'a>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Renderable>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Movable>,
+        ReadStorage<'a, Immovable>,
+        ReadStorage<'a, Ice>,
+        ReadStorage<'a, Teleporter>,
+        ReadStorage<'a, OneWay>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (gameplay_state, time, positions, renderables) = data;
+        let (
+            time,
+            box_drag,
+            overlay,
+            level_info,
+            active_player,
+            reverse_mode,
+            topology,
+            move_tween,
+            entities,
+            positions,
+            renderables,
+            players,
+            movables,
+            immovables,
+            ices,
+            teleporters,
+            one_ways,
+        ) = data;
 
         // Clear the screen (set the background colour)
         graphics::clear(self.context, Color::new(0.95, 0.95, 0.95, 1.0));
 
         // Get all renderables and sort by z-index position (for layering elements)
-        let rendering_data = (&positions, &renderables).join().collect::<Vec<_>>();
+        let rendering_data = (&entities, &positions, &renderables)
+            .join()
+            .collect::<Vec<_>>();
         let mut rendering_batches: HashMap<u8, HashMap<String, Vec<DrawParam>>> = HashMap::new();
 
         // Iterate through renderables and add to the batches
-        for (position, renderable) in rendering_data.iter() {
-            let image_path = self.get_image(renderable, time.delta);
+        for (entity, position, renderable) in rendering_data.iter() {
+            let image_path = self.get_image(renderable, time.since_start);
 
-            let calc_pos = |num: f32| num * TILE_WIDTH;
-            let x = calc_pos(position.x as f32);
-            let y = calc_pos(position.y as f32);
+            let (mut x, mut y, scale) = topology.layout((position.x, position.y), TILE_WIDTH);
+            if let Some(from) = move_tween.from.get(&entity.id()) {
+                let (from_x, from_y, _scale) = topology.layout(*from, TILE_WIDTH);
+                let progress = move_tween.progress();
+                x = from_x + (x - from_x) * progress;
+                y = from_y + (y - from_y) * progress;
+            }
 
-            let draw_params = DrawParam::new().dest(na::Point2::new(x, y));
+            let mut draw_params = DrawParam::new()
+                .dest(na::Point2::new(x, y))
+                .scale(na::Vector2::new(scale, scale));
+            if let Some((r, g, b)) = renderable.tint {
+                draw_params = draw_params.color(Color::new(r, g, b, 1.0));
+            }
 
             rendering_batches
                 .entry(position.z)
@@ -57,6 +104,7 @@ impl<'a> System<'a> for RenderingSystem<'a> {
             .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
         {
             for (image_path, draw_params) in batch {
+                let image_path = self.themed(level_info.meta.theme.as_deref(), image_path);
                 let image = Image::new(self.context, image_path).expect("Expected image");
 
                 let mut sprite_batch = SpriteBatch::new(image);
@@ -69,30 +117,127 @@ impl<'a> System<'a> for RenderingSystem<'a> {
             }
         }
 
-        self.draw_text(&gameplay_state.state.to_string(), 525.0, 80.0);
-        self.draw_text(&gameplay_state.moves_count.to_string(), 525.0, 100.0);
-        let fps = format!("FPS: {}", timer::fps(self.context));
-        self.draw_text(&fps.to_string(), 525.0, 120.0);
+        // Triangles are drawn as half size sprites, so outline each cell to show which way it points
+        if *topology == Topology::Triangle {
+            self.draw_triangles(&positions);
+        }
+
+        // In reverse play the spots are where the boxes start, so mark where they have to go back to
+        for (cell, colour) in reverse_mode.targets.iter() {
+            let (r, g, b) = colour.tint().unwrap_or((0.55, 0.35, 0.2));
+            self.draw_marker(*topology, *cell, Color::new(r, g, b, 0.8));
+        }
+
+        // With more than one player, mark the one that moves
+        let active = active_player
+            .entity(&entities, &players)
+            .and_then(|active| positions.get(active));
+        if let Some(position) = active.filter(|_| (&players).join().count() > 1) {
+            self.draw_marker(
+                *topology,
+                (position.x, position.y),
+                Color::new(1.0, 0.85, 0.1, 0.9),
+            );
+        }
+
+        // Overlay pass: the player's reachable area, then where the selected box can go
+        if overlay.enabled {
+            let board = Board::new(&entities, &movables, &immovables, &positions).with_terrain(
+                Terrain::new(&ices, &teleporters, &one_ways, &positions).with_topology(*topology),
+            );
+
+            if let Some(position) = active {
+                let player = (position.x, position.y);
 
-        graphics::present(self.context).expect("Expected to present");
+                for cell in reachable_cells(&board, player) {
+                    self.draw_highlight(*topology, cell, Color::new(0.3, 0.5, 0.9, 0.3));
+                }
+
+                // Push targets mean nothing when boxes are pulled
+                let selected = overlay
+                    .selected_box
+                    .filter(|_| !reverse_mode.enabled)
+                    .and_then(|selected| positions.get(selected));
+                if let Some(box_position) = selected {
+                    let box_cell = (box_position.x, box_position.y);
+                    for cell in push_targets(&board, player, box_cell) {
+                        self.draw_highlight(*topology, cell, Color::new(0.95, 0.6, 0.1, 0.45));
+                    }
+                }
+            }
+        }
+
+        // Show whether the box being dragged can be pushed to the cell under the cursor
+        if let Some(from) = box_drag.from {
+            if box_drag.target != from {
+                let colour = if box_drag.reachable {
+                    Color::new(0.2, 0.8, 0.2, 0.5)
+                } else {
+                    Color::new(0.9, 0.2, 0.2, 0.5)
+                };
+                self.draw_highlight(*topology, box_drag.target, colour);
+            }
+        }
     }
 }
 
 impl RenderingSystem<'_> {
-    pub fn draw_text(&mut self, text_string: &str, x: f32, y: f32) {
-        let text = graphics::Text::new(text_string);
-        let destination = na::Point2::new(x, y);
-        let colour = Some(Color::new(0.0, 0.0, 0.0, 1.0));
-        let dimensions = na::Point2::new(0.0, 20.0);
-
-        graphics::queue_text(self.context, &text, dimensions, colour);
-        graphics::draw_queued_text(
-            self.context,
-            graphics::DrawParam::new().dest(destination),
-            None,
-            graphics::FilterMode::Linear,
-        )
-        .expect("Expected draw text");
+    pub fn draw_highlight(&mut self, topology: Topology, cell: (u8, u8), colour: Color) {
+        let rect = cell_rect(topology, cell);
+        let mesh = Mesh::new_rectangle(self.context, DrawMode::fill(), rect, colour)
+            .expect("Expected highlight mesh");
+
+        graphics::draw(self.context, &mesh, graphics::DrawParam::new()).expect("Expected render");
+    }
+
+    pub fn draw_marker(&mut self, topology: Topology, cell: (u8, u8), colour: Color) {
+        let mut rect = cell_rect(topology, cell);
+        rect.translate(na::Vector2::new(1.5, 1.5));
+        rect.scale(1.0 - 3.0 / rect.w, 1.0 - 3.0 / rect.h);
+        let mesh = Mesh::new_rectangle(self.context, DrawMode::stroke(3.0), rect, colour)
+            .expect("Expected marker mesh");
+
+        graphics::draw(self.context, &mesh, graphics::DrawParam::new()).expect("Expected render");
+    }
+
+    pub fn draw_triangles(&mut self, positions: &ReadStorage<Position>) {
+        let cells = positions
+            .join()
+            .map(|position| (position.x, position.y))
+            .collect::<HashSet<_>>();
+        if cells.is_empty() {
+            return;
+        }
+
+        let mut builder = MeshBuilder::new();
+        for cell in cells {
+            let corners = Topology::Triangle
+                .triangle(cell, TILE_WIDTH)
+                .iter()
+                .map(|(x, y)| na::Point2::new(*x, *y))
+                .collect::<Vec<_>>();
+            builder
+                .polygon(
+                    DrawMode::stroke(1.0),
+                    &corners,
+                    Color::new(0.3, 0.3, 0.3, 0.6),
+                )
+                .expect("Expected triangle outline");
+        }
+        let mesh = builder.build(self.context).expect("Expected triangle mesh");
+
+        graphics::draw(self.context, &mesh, graphics::DrawParam::new()).expect("Expected render");
+    }
+
+    // A theme only needs to supply the images it changes
+    fn themed(&mut self, theme: Option<&str>, path: &str) -> String {
+        if let Some(theme) = theme {
+            let themed = format!("/themes/{}{}", theme, path);
+            if filesystem::exists(self.context, &themed) {
+                return themed;
+            }
+        }
+        path.to_string()
     }
 
     pub fn get_image(&mut self, renderable: &Renderable, delta: Duration) -> String {
@@ -104,3 +249,9 @@ impl RenderingSystem<'_> {
         renderable.path(path_index).to_string()
     }
 }
+
+// The square a cell's sprite covers
+fn cell_rect(topology: Topology, cell: (u8, u8)) -> Rect {
+    let (x, y, scale) = topology.layout(cell, TILE_WIDTH);
+    Rect::new(x, y, TILE_WIDTH * scale, TILE_WIDTH * scale)
+}
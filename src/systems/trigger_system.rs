@@ -0,0 +1,147 @@
+use crate::components::*;
+use crate::entities::door_renderable;
+use crate::events::Event;
+use crate::resources::EventQueue;
+use specs::{join::Join, Entities, Entity, ReadStorage, System, Write, WriteStorage};
+use std::collections::{HashMap, HashSet};
+
+pub struct TriggerSystem {}
+
+impl<'a> System<'a> for TriggerSystem {
+    type SystemData = (
+        Write<'a, EventQueue>,
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Immovable>,
+        WriteStorage<'a, Renderable>,
+        WriteStorage<'a, Key>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Box>,
+        ReadStorage<'a, Switch>,
+        ReadStorage<'a, Door>,
+    );
+
+    // Everything here follows from where things stand, so undo, which moves things without
+    // any events, gets doors put back just the same. Moves already do this as each one is
+    // applied, which leaves keys placed under a player by a loaded level
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            mut event_queue,
+            entities,
+            mut positions,
+            mut immovables,
+            mut renderables,
+            mut keys,
+            players,
+            boxes,
+            switches,
+            doors,
+        ) = data;
+
+        let standing = (&entities, &positions, &players)
+            .join()
+            .map(|t| (t.0, (t.1.x, t.1.y)))
+            .collect::<Vec<_>>();
+        for (player, cell) in standing {
+            pick_up_keys(
+                player,
+                &[cell],
+                &mut event_queue,
+                &entities,
+                &mut keys,
+                &mut positions,
+            );
+        }
+
+        let states = door_states(&entities, &positions, &keys, &boxes, &switches, &doors);
+        update_doors(
+            states,
+            &mut event_queue,
+            &doors,
+            &mut immovables,
+            &mut renderables,
+        );
+    }
+}
+
+// Keys on any of `cells` are picked up by `holder`, which takes them off the board
+pub fn pick_up_keys(
+    holder: Entity,
+    cells: &[(u8, u8)],
+    event_queue: &mut EventQueue,
+    entities: &Entities,
+    keys: &mut WriteStorage<Key>,
+    positions: &mut WriteStorage<Position>,
+) {
+    let picked_up = (entities, &*keys, &*positions)
+        .join()
+        .filter(|t| cells.contains(&(t.2.x, t.2.y)))
+        .map(|t| t.0)
+        .collect::<Vec<_>>();
+    for entity in picked_up {
+        positions.remove(entity);
+        if let Some(key) = keys.get_mut(entity) {
+            key.holder = Some(holder);
+        }
+        event_queue.events.push(Event::KeyPickedUp);
+    }
+}
+
+// Each door and whether it should be open, from the boxes on the switches and the keys held
+pub fn door_states(
+    entities: &Entities,
+    positions: &WriteStorage<Position>,
+    keys: &WriteStorage<Key>,
+    boxes: &ReadStorage<Box>,
+    switches: &ReadStorage<Switch>,
+    doors: &ReadStorage<Door>,
+) -> Vec<(Entity, bool)> {
+    let held: HashSet<u8> = (keys, !positions).join().map(|t| t.0.link).collect();
+
+    // A link's switches are down when every one of them has a box on it
+    let box_cells: HashSet<(u8, u8)> = (positions, boxes).join().map(|t| (t.0.x, t.0.y)).collect();
+    let mut switches_down: HashMap<u8, bool> = HashMap::new();
+    for (switch, position) in (switches, positions).join() {
+        *switches_down.entry(switch.link).or_insert(true) &=
+            box_cells.contains(&(position.x, position.y));
+    }
+
+    (entities, doors)
+        .join()
+        .map(|(entity, door)| {
+            let open = match door.kind {
+                DoorKind::Switch => switches_down.get(&door.link).copied().unwrap_or(false),
+                DoorKind::Key => held.contains(&door.link),
+            };
+            (entity, open)
+        })
+        .collect()
+}
+
+pub fn update_doors(
+    states: Vec<(Entity, bool)>,
+    event_queue: &mut EventQueue,
+    doors: &ReadStorage<Door>,
+    immovables: &mut WriteStorage<Immovable>,
+    renderables: &mut WriteStorage<Renderable>,
+) {
+    for (entity, open) in states {
+        let door = match doors.get(entity) {
+            Some(door) => door,
+            None => continue,
+        };
+        if open != immovables.contains(entity) {
+            continue;
+        }
+
+        // A door closing on something in the doorway still lets it step out
+        if open {
+            immovables.remove(entity);
+            event_queue.events.push(Event::DoorOpened);
+        } else {
+            let _ = immovables.insert(entity, Immovable);
+            event_queue.events.push(Event::DoorClosed);
+        }
+        let _ = renderables.insert(entity, door_renderable(door.link, door.kind, open));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::actions::Direction;
+use serde::{Deserialize, Serialize};
+
+// The shape of a level's cells, which decides what neighbours what and where each cell is drawn.
+// Hex levels shift every odd row half a cell to the right. Triangles alternate pointing up and
+// down along each row, starting with one pointing up in the top left corner, and share the hex
+// directions: a diagonal zig-zags across the edge above or below and the one to its side in turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    #[default]
+    Square,
+    Hex,
+    Triangle,
+}
+
+impl Topology {
+    pub fn from_name(name: &str) -> Option<Topology> {
+        match name.to_lowercase().as_str() {
+            "square" => Some(Topology::Square),
+            "hex" | "hexoban" => Some(Topology::Hex),
+            "triangle" | "trioban" => Some(Topology::Triangle),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Topology::Square => "square",
+            Topology::Hex => "hex",
+            Topology::Triangle => "triangle",
+        }
+    }
+
+    pub fn is_square(&self) -> bool {
+        *self == Topology::Square
+    }
+
+    // Every direction that can lead somewhere
+    pub fn directions(self) -> &'static [Direction] {
+        match self {
+            Topology::Square => &Direction::SQUARE,
+            Topology::Hex | Topology::Triangle => &Direction::HEX,
+        }
+    }
+
+    // The neighbouring cell in this direction, if there is one and it is still on the grid
+    pub fn step(self, (x, y): (u8, u8), direction: Direction) -> Option<(u8, u8)> {
+        let odd_row = y % 2 == 1;
+        let points_up = (x as u32 + y as u32) % 2 == 0;
+
+        match (self, direction) {
+            (_, Direction::Left) => Some((x.checked_sub(1)?, y)),
+            (_, Direction::Right) => Some((x.checked_add(1)?, y)),
+            (Topology::Square, Direction::Up) => Some((x, y.checked_sub(1)?)),
+            (Topology::Square, Direction::Down) => Some((x, y.checked_add(1)?)),
+            // Only a triangle pointing down has an edge above it, and only one pointing up has one
+            // below, so a diagonal crosses that edge when there is one and goes sideways otherwise
+            (Topology::Triangle, Direction::UpLeft) | (Topology::Triangle, Direction::UpRight)
+                if !points_up =>
+            {
+                Some((x, y.checked_sub(1)?))
+            }
+            (Topology::Triangle, Direction::DownLeft)
+            | (Topology::Triangle, Direction::DownRight)
+                if points_up =>
+            {
+                Some((x, y.checked_add(1)?))
+            }
+            (Topology::Triangle, Direction::UpLeft) | (Topology::Triangle, Direction::DownLeft) => {
+                Some((x.checked_sub(1)?, y))
+            }
+            (Topology::Triangle, Direction::UpRight)
+            | (Topology::Triangle, Direction::DownRight) => Some((x.checked_add(1)?, y)),
+            (Topology::Hex, Direction::UpLeft) | (Topology::Hex, Direction::DownLeft) => {
+                let x = if odd_row { x } else { x.checked_sub(1)? };
+                let y = match direction {
+                    Direction::UpLeft => y.checked_sub(1)?,
+                    _ => y.checked_add(1)?,
+                };
+                Some((x, y))
+            }
+            (Topology::Hex, Direction::UpRight) | (Topology::Hex, Direction::DownRight) => {
+                let x = if odd_row { x.checked_add(1)? } else { x };
+                let y = match direction {
+                    Direction::UpRight => y.checked_sub(1)?,
+                    _ => y.checked_add(1)?,
+                };
+                Some((x, y))
+            }
+            _ => None,
+        }
+    }
+
+    // Where a cell's sprite goes, as its top left corner and a scale, with `tile` the size of a square cell
+    pub fn layout(self, (x, y): (u8, u8), tile: f32) -> (f32, f32, f32) {
+        let (x, y, row) = (x as f32, y as f32, y as u32);
+        match self {
+            Topology::Square => (x * tile, y * tile, 1.0),
+            Topology::Hex => (x * tile + (row % 2) as f32 * tile / 2.0, y * tile, 1.0),
+            // Triangles overlap their neighbours by half, so a half size sprite sits in the wide half
+            Topology::Triangle => {
+                let points_up = (x as u32 + row) % 2 == 0;
+                let top = if points_up { tile / 2.0 } else { 0.0 };
+                (x * tile / 2.0 + tile / 4.0, y * tile + top, 0.5)
+            }
+        }
+    }
+
+    // The size of a grid of `width` by `height` cells once laid out
+    pub fn extent(self, width: u8, height: u8, tile: f32) -> (f32, f32) {
+        let (width, height) = (width as f32, height as f32);
+        match self {
+            Topology::Square => (width * tile, height * tile),
+            Topology::Hex => ((width + 0.5) * tile, height * tile),
+            Topology::Triangle => ((width + 1.0) * tile / 2.0, height * tile),
+        }
+    }
+
+    // The corners of a triangle cell, for outlining it
+    pub fn triangle(self, (x, y): (u8, u8), tile: f32) -> [(f32, f32); 3] {
+        let (left, top) = (x as f32 * tile / 2.0, y as f32 * tile);
+        let (right, bottom, middle) = (left + tile, top + tile, left + tile / 2.0);
+        if (x as u32 + y as u32) % 2 == 0 {
+            [(left, bottom), (middle, top), (right, bottom)]
+        } else {
+            [(left, top), (right, top), (middle, bottom)]
+        }
+    }
+
+    // The cell under a point on screen, the reverse of `layout`
+    pub fn cell_at(self, x: f32, y: f32, tile: f32) -> (u8, u8) {
+        let row = (y / tile) as u8;
+        match self {
+            Topology::Square => ((x / tile) as u8, row),
+            Topology::Hex => {
+                let shift = (row % 2) as f32 * tile / 2.0;
+                (((x - shift).max(0.0) / tile) as u8, row)
+            }
+            // Each half tile column holds the sloping edge between two triangles
+            Topology::Triangle => {
+                let column = x / (tile / 2.0);
+                let (index, across) = (column as u8, column.fract());
+                let down = (y / tile).fract();
+                let points_up = (index as u32 + row as u32) % 2 == 0;
+                let inside = if points_up {
+                    down >= 1.0 - across
+                } else {
+                    down <= across
+                };
+                if inside || index == 0 {
+                    (index, row)
+                } else {
+                    (index - 1, row)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_step_is_undone_by_the_opposite_direction() {
+        for topology in [Topology::Square, Topology::Hex, Topology::Triangle] {
+            for (x, y) in (1..5).flat_map(|x| (1..5).map(move |y| (x, y))) {
+                for direction in topology.directions().iter().copied() {
+                    let next = topology.step((x, y), direction).expect("Expected a cell");
+                    assert_eq!(
+                        topology.step(next, direction.opposite()),
+                        Some((x, y)),
+                        "{:?} {:?} from {:?}",
+                        topology,
+                        direction,
+                        (x, y)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_diagonals_zig_zag() {
+        let mut cell = (2, 1);
+        let mut line = Vec::new();
+        for _ in 0..4 {
+            cell = Topology::Triangle
+                .step(cell, Direction::DownRight)
+                .expect("Expected a cell");
+            line.push(cell);
+        }
+        assert_eq!(line, vec![(3, 1), (3, 2), (4, 2), (4, 3)]);
+    }
+}